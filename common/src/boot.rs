@@ -1,18 +1,40 @@
-use uefi::table::boot::MemoryDescriptor;
+pub use uefi::table::boot::{MemoryDescriptor, MemoryType};
+
+/// カーネルをリンクする仮想アドレス(x64.ldの`KERNEL_BASE`と合わせる)
+/// ここから1GiBを物理アドレス0からにマップする
+pub const KERNEL_BASE: u64 = 0xffff_ffff_8000_0000;
+
+/// 物理メモリ全体をストレートマップする仮想アドレス
+pub const PHYS_MEMORY_OFFSET: u64 = 0xffff_8000_0000_0000;
+
+/// ローダーが作ったページテーブルを置くメモリの種類
+/// カーネルはこのページテーブルを使い続けるので、空きメモリとして扱わない
+pub const PAGE_TABLE_MEMORY: MemoryType = MemoryType::custom(0x8000_0000);
 
 #[derive(Debug, Clone, Copy)]
 pub struct BootInfo {
     mmap: MemoryMap,
+    /// ACPI 2.0のRSDPの物理アドレス(見つからなかった場合は0)
+    rsdp: u64,
 }
 
 impl BootInfo {
-    pub fn new(mmap: MemoryMap) -> Self {
-        Self { mmap }
+    pub fn new(mmap: MemoryMap, rsdp: u64) -> Self {
+        Self { mmap, rsdp }
     }
 
     pub fn mmap(&self) -> &MemoryMap {
         &self.mmap
     }
+
+    /// ACPI 2.0のRSDPの物理アドレスを返す
+    pub fn rsdp(&self) -> Option<u64> {
+        if self.rsdp != 0 {
+            Some(self.rsdp)
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -25,6 +47,21 @@ impl MemoryMap {
     pub fn new(mem_desc: *const MemoryDescriptor, len: u64) -> Self {
         Self { mem_desc, len }
     }
+
+    /// メモリディスクリプタの配列の先頭アドレスを返す
+    pub fn as_ptr(&self) -> *const MemoryDescriptor {
+        self.mem_desc
+    }
+
+    /// 残っているメモリディスクリプタの数を返す
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// メモリディスクリプタが残っていないかを返す
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
 }
 
 impl Iterator for MemoryMap {
@@ -0,0 +1,327 @@
+use crate::memory::phys_to_virt;
+use alloc::vec::Vec;
+use kani2_common::boot::BootInfo;
+use spin::Once;
+use x86_64::PhysAddr;
+
+static ACPI_TABLES: Once<AcpiTables> = Once::new();
+
+/// RSDP(ACPI 2.0以降)
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// 全てのシステム記述テーブルに共通するヘッダ
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+const SDT_HEADER_SIZE: u64 = core::mem::size_of::<SdtHeader>() as u64;
+
+/// カーネルが使うACPIテーブルの内容
+#[derive(Debug, Default)]
+pub struct AcpiTables {
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    /// PCI Expressのコンフィグレーション空間(MCFG)
+    pub mcfg: Vec<McfgEntry>,
+}
+
+/// MADT(Multiple APIC Description Table)
+#[derive(Debug, Default)]
+pub struct Madt {
+    /// Local APICのレジスタの物理アドレス
+    pub local_apic_address: u64,
+    /// 8259 PICも載っているか
+    pub pcat_compat: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptSourceOverride>,
+}
+
+/// MADTに載っているプロセッサ
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub acpi_id: u32,
+    pub apic_id: u32,
+    /// 使用可能か(使用不可でもオンラインにできるものを含む)
+    pub enabled: bool,
+}
+
+/// MADTに載っているI/O APIC
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub id: u8,
+    /// レジスタの物理アドレス
+    pub address: u64,
+    /// 最初の入力ピンに対応するGSI
+    pub gsi_base: u32,
+}
+
+/// ISAのIRQとGSIの対応の上書き
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub bus: u8,
+    /// ISAのIRQ番号
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+/// 割り込みの極性
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    /// バスの規定に従う
+    Conforming,
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// 割り込みのトリガーモード
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// バスの規定に従う
+    Conforming,
+    Edge,
+    Level,
+}
+
+impl InterruptSourceOverride {
+    pub fn polarity(&self) -> Polarity {
+        match self.flags & 0b11 {
+            0b01 => Polarity::ActiveHigh,
+            0b11 => Polarity::ActiveLow,
+            _ => Polarity::Conforming,
+        }
+    }
+
+    pub fn trigger_mode(&self) -> TriggerMode {
+        match (self.flags >> 2) & 0b11 {
+            0b01 => TriggerMode::Edge,
+            0b11 => TriggerMode::Level,
+            _ => TriggerMode::Conforming,
+        }
+    }
+}
+
+/// FADT(Fixed ACPI Description Table)のうちカーネルが使うもの
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    /// SCI割り込みのISA IRQ番号
+    pub sci_interrupt: u16,
+    /// ACPI PMタイマーのI/Oポート
+    pub pm_timer_block: u32,
+    /// IA-PC boot architecture flags
+    pub iapc_boot_arch: u16,
+    pub flags: u32,
+}
+
+/// HPETの情報
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    /// レジスタの物理アドレス
+    pub address: u64,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+}
+
+/// MCFGのエントリ
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    /// ECAMの物理アドレス
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+unsafe fn read<T: Copy>(phys: u64) -> T {
+    core::ptr::read_unaligned(phys_to_virt(PhysAddr::new(phys)).as_ptr::<T>())
+}
+
+/// 全バイトの和が0になっているか確かめる
+unsafe fn checksum_ok(phys: u64, len: u64) -> bool {
+    (0..len).fold(0u8, |sum, i| sum.wrapping_add(read::<u8>(phys + i))) == 0
+}
+
+/// ローダから渡されたRSDPを起点にACPIテーブルを読む
+pub fn init(boot_info: &BootInfo) {
+    let rsdp = match boot_info.rsdp() {
+        Some(rsdp) => rsdp,
+        None => return,
+    };
+    if let Some(tables) = unsafe { AcpiTables::parse(rsdp) } {
+        ACPI_TABLES.call_once(|| tables);
+    }
+}
+
+/// 読み込んだACPIテーブルを返す
+pub fn tables() -> Option<&'static AcpiTables> {
+    ACPI_TABLES.get()
+}
+
+pub fn madt() -> Option<&'static Madt> {
+    tables()?.madt.as_ref()
+}
+
+pub fn fadt() -> Option<&'static Fadt> {
+    tables()?.fadt.as_ref()
+}
+
+pub fn hpet() -> Option<&'static Hpet> {
+    tables()?.hpet.as_ref()
+}
+
+pub fn mcfg() -> &'static [McfgEntry] {
+    tables().map(|tables| tables.mcfg.as_slice()).unwrap_or(&[])
+}
+
+impl AcpiTables {
+    unsafe fn parse(rsdp_address: u64) -> Option<Self> {
+        let rsdp = read::<Rsdp>(rsdp_address);
+        if &rsdp.signature != b"RSD PTR " || !checksum_ok(rsdp_address, 20) {
+            return None;
+        }
+
+        // ACPI 2.0以降ならXSDT(64bitのエントリ)、そうでなければRSDT(32bitのエントリ)を読む
+        let (sdt_address, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+            (rsdp.xsdt_address, 8)
+        } else {
+            (rsdp.rsdt_address as u64, 4)
+        };
+        let sdt = read::<SdtHeader>(sdt_address);
+        if !checksum_ok(sdt_address, sdt.length as u64) {
+            return None;
+        }
+
+        let mut tables = Self::default();
+        let count = (sdt.length as u64 - SDT_HEADER_SIZE) / entry_size;
+        for i in 0..count {
+            let entry = sdt_address + SDT_HEADER_SIZE + i * entry_size;
+            let table = if entry_size == 8 {
+                read::<u64>(entry)
+            } else {
+                read::<u32>(entry) as u64
+            };
+            let header = read::<SdtHeader>(table);
+            if !checksum_ok(table, header.length as u64) {
+                continue;
+            }
+
+            match &header.signature {
+                b"APIC" => tables.madt = Some(parse_madt(table, &header)),
+                b"FACP" => tables.fadt = Some(parse_fadt(table)),
+                b"HPET" => tables.hpet = Some(parse_hpet(table)),
+                b"MCFG" => tables.mcfg = parse_mcfg(table, &header),
+                _ => {}
+            }
+        }
+        Some(tables)
+    }
+}
+
+unsafe fn parse_madt(table: u64, header: &SdtHeader) -> Madt {
+    let mut madt = Madt {
+        local_apic_address: read::<u32>(table + 36) as u64,
+        pcat_compat: read::<u32>(table + 40) & 1 != 0,
+        ..Madt::default()
+    };
+
+    let end = table + header.length as u64;
+    let mut entry = table + 44;
+    while entry + 2 <= end {
+        let ty = read::<u8>(entry);
+        let len = read::<u8>(entry + 1) as u64;
+        if len < 2 {
+            break;
+        }
+
+        match ty {
+            // Processor Local APIC
+            0 => madt.processors.push(Processor {
+                acpi_id: read::<u8>(entry + 2) as u32,
+                apic_id: read::<u8>(entry + 3) as u32,
+                enabled: read::<u32>(entry + 4) & 0b11 != 0,
+            }),
+            // I/O APIC
+            1 => madt.io_apics.push(IoApicEntry {
+                id: read::<u8>(entry + 2),
+                address: read::<u32>(entry + 4) as u64,
+                gsi_base: read::<u32>(entry + 8),
+            }),
+            // Interrupt Source Override
+            2 => madt.overrides.push(InterruptSourceOverride {
+                bus: read::<u8>(entry + 2),
+                source: read::<u8>(entry + 3),
+                gsi: read::<u32>(entry + 4),
+                flags: read::<u16>(entry + 8),
+            }),
+            // Local APIC Address Override
+            5 => madt.local_apic_address = read::<u64>(entry + 4),
+            // Processor Local x2APIC
+            9 => madt.processors.push(Processor {
+                acpi_id: read::<u32>(entry + 12),
+                apic_id: read::<u32>(entry + 4),
+                enabled: read::<u32>(entry + 8) & 0b11 != 0,
+            }),
+            _ => {}
+        }
+        entry += len;
+    }
+    madt
+}
+
+unsafe fn parse_fadt(table: u64) -> Fadt {
+    Fadt {
+        sci_interrupt: read::<u16>(table + 46),
+        pm_timer_block: read::<u32>(table + 76),
+        iapc_boot_arch: read::<u16>(table + 109),
+        flags: read::<u32>(table + 112),
+    }
+}
+
+unsafe fn parse_hpet(table: u64) -> Hpet {
+    Hpet {
+        // Generic Address Structureのアドレス部分
+        address: read::<u64>(table + 44),
+        hpet_number: read::<u8>(table + 52),
+        minimum_tick: read::<u16>(table + 53),
+    }
+}
+
+unsafe fn parse_mcfg(table: u64, header: &SdtHeader) -> Vec<McfgEntry> {
+    let end = table + header.length as u64;
+    let mut entries = Vec::new();
+    let mut entry = table + 44;
+    while entry + 16 <= end {
+        entries.push(McfgEntry {
+            base_address: read::<u64>(entry),
+            segment: read::<u16>(entry + 8),
+            start_bus: read::<u8>(entry + 10),
+            end_bus: read::<u8>(entry + 11),
+        });
+        entry += 16;
+    }
+    entries
+}
@@ -0,0 +1,404 @@
+use crate::{
+    buddy,
+    memory::{self, phys_to_virt, PAGE_FRAME_MANAGER, PAGE_SIZE},
+};
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, MappedFrame, TranslateResult, UnmapError},
+        Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB,
+        Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// ユーザー空間の先頭
+/// ヌルポインタの参照を捕まえるため、先頭の4MiBはマップしない
+pub const USER_SPACE_START: u64 = 0x0000_0000_0040_0000;
+/// ユーザー空間の終端
+/// 下位半分の最後のページは使わせない
+/// そこにあるSYSCALL命令から戻ろうとすると、戻り先が非正規アドレスになり、
+/// SYSRETQがユーザーのスタックとGSのままリング0で#GPを起こしてしまう(Intel)
+pub const USER_SPACE_END: u64 = 0x0000_7fff_ffff_f000;
+
+/// アドレスを指定しないmmapで使い始めるアドレス
+const MMAP_BASE: u64 = 0x0000_1000_0000_0000;
+
+/// アドレス空間が確保したフレームを指すエントリにつける印
+/// 解放するときに、これがついたフレームだけを返す
+const OWNED: PageTableFlags = PageTableFlags::BIT_9;
+
+/// ページテーブルの操作に失敗した理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// 物理フレームが足りない
+    OutOfMemory,
+    /// ユーザー空間に収まっていないか、ページ境界に揃っていない
+    InvalidRange,
+    /// すでにマップされている
+    AlreadyMapped,
+    /// マップされていないか、違う大きさのページでマップされている
+    NotMapped,
+}
+
+impl<S: PageSize> From<MapToError<S>> for MapError {
+    fn from(err: MapToError<S>) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => MapError::OutOfMemory,
+            MapToError::PageAlreadyMapped(_) | MapToError::ParentEntryHugePage => {
+                MapError::AlreadyMapped
+            }
+        }
+    }
+}
+
+impl From<UnmapError> for MapError {
+    fn from(_: UnmapError) -> Self {
+        MapError::NotMapped
+    }
+}
+
+impl From<FlagUpdateError> for MapError {
+    fn from(_: FlagUpdateError) -> Self {
+        MapError::NotMapped
+    }
+}
+
+/// ユーザータスクのアドレス空間
+/// カーネルの部分はカーネルのページテーブルと共有し、ユーザー空間の部分だけを持つ
+#[derive(Debug)]
+pub struct AddressSpace {
+    pml4: PhysFrame,
+    /// アドレスを指定しないmmapで次に使うアドレス
+    mmap_next: u64,
+}
+
+impl AddressSpace {
+    /// カーネルの部分だけをマップしたアドレス空間を作る
+    pub fn new() -> Option<Self> {
+        let pml4 = PAGE_FRAME_MANAGER.lock().allocate()?;
+        let space = Self {
+            pml4,
+            mmap_next: MMAP_BASE,
+        };
+        let kernel = unsafe {
+            &*phys_to_virt(memory::kernel_p4_frame().start_address()).as_ptr::<PageTable>()
+        };
+        let table = space.table();
+        table.zero();
+        for (i, entry) in kernel.iter().enumerate() {
+            if !is_user_pml4_index(i) {
+                table[i] = entry.clone();
+            }
+        }
+        Some(space)
+    }
+
+    /// CR3に設定するPML4の物理フレーム
+    pub fn p4_frame(&self) -> PhysFrame {
+        self.pml4
+    }
+
+    fn table(&self) -> &'static mut PageTable {
+        unsafe { &mut *phys_to_virt(self.pml4.start_address()).as_mut_ptr() }
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe { OffsetPageTable::new(self.table(), phys_to_virt(PhysAddr::zero())) }
+    }
+
+    /// `[start, start + size)`がページ境界に揃っていて、ユーザー空間に収まっているか
+    pub fn is_user_range(start: VirtAddr, size: u64) -> bool {
+        start.is_aligned(PAGE_SIZE)
+            && size.is_multiple_of(PAGE_SIZE)
+            && start.as_u64() >= USER_SPACE_START
+            && start
+                .as_u64()
+                .checked_add(size)
+                .is_some_and(|end| end <= USER_SPACE_END)
+    }
+
+    /// `page`に`frame`を`flags`でマップする
+    /// `frame`はアドレス空間を破棄しても解放しない
+    pub fn map<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError>
+    where
+        for<'a> OffsetPageTable<'a>: Mapper<S>,
+    {
+        self.map_frame(page, frame, flags - OWNED)
+    }
+
+    /// `page`にゼロで埋めたフレームを割り当て、`flags`でマップする
+    /// 2MiBと1GiBのページのフレームはバディアロケータから確保する
+    pub fn map_anonymous_page<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError>
+    where
+        for<'a> OffsetPageTable<'a>: Mapper<S>,
+    {
+        let frame = allocate_frame::<S>().ok_or(MapError::OutOfMemory)?;
+        unsafe {
+            phys_to_virt(frame.start_address())
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, S::SIZE as usize);
+        }
+        self.map_frame(page, frame, flags | OWNED)
+            .inspect_err(|_| free_frame(frame.start_address(), S::SIZE))
+    }
+
+    fn map_frame<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError>
+    where
+        for<'a> OffsetPageTable<'a>: Mapper<S>,
+    {
+        if !Self::is_user_range(page.start_address(), S::SIZE) {
+            return Err(MapError::InvalidRange);
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let mut frames = PAGE_FRAME_MANAGER.lock();
+        unsafe {
+            self.mapper()
+                .map_to(page, frame, flags, &mut *frames)?
+                .flush();
+        }
+        Ok(())
+    }
+
+    /// `[start, start + size)`にゼロで埋めた4KiBのフレームを割り当て、`flags`でマップする
+    /// 失敗したら、途中までマップしたページは外す
+    pub fn map_anonymous(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        if !Self::is_user_range(start, size) {
+            return Err(MapError::InvalidRange);
+        }
+        for offset in (0..size).step_by(PAGE_SIZE as usize) {
+            let page = Page::<Size4KiB>::containing_address(start + offset);
+            if let Err(err) = self.map_anonymous_page(page, flags) {
+                self.unmap_range(start, offset);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// `page`のマップを外す
+    /// アドレス空間が確保したフレームなら解放する
+    pub fn unmap<S: PageSize>(&mut self, page: Page<S>) -> Result<(), MapError>
+    where
+        for<'a> OffsetPageTable<'a>: Mapper<S>,
+    {
+        if !Self::is_user_range(page.start_address(), S::SIZE) {
+            return Err(MapError::InvalidRange);
+        }
+        let owned = self
+            .flags(page.start_address())
+            .is_some_and(|flags| flags.contains(OWNED));
+        let (frame, flush) = without_interrupts(|| self.mapper().unmap(page))?;
+        flush.flush();
+        if owned {
+            free_frame(frame.start_address(), S::SIZE);
+        }
+        Ok(())
+    }
+
+    /// `[start, start + size)`のマップを外す
+    /// 範囲に一部でもかかる大きいページは丸ごと外し、マップされていないページは無視する
+    pub fn unmap_range(&mut self, start: VirtAddr, size: u64) {
+        let end = start + size;
+        let mut addr = start.align_down(PAGE_SIZE);
+        while addr < end {
+            let mapped = self.mapper().translate(addr);
+            let next = match mapped {
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size1GiB(_),
+                    ..
+                } => {
+                    let page = Page::<Size1GiB>::containing_address(addr);
+                    let _ = self.unmap(page);
+                    page.start_address() + page.size()
+                }
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size2MiB(_),
+                    ..
+                } => {
+                    let page = Page::<Size2MiB>::containing_address(addr);
+                    let _ = self.unmap(page);
+                    page.start_address() + page.size()
+                }
+                TranslateResult::Mapped { .. } => {
+                    let _ = self.unmap(Page::<Size4KiB>::containing_address(addr));
+                    addr + PAGE_SIZE
+                }
+                _ => addr + PAGE_SIZE,
+            };
+            addr = next;
+        }
+    }
+
+    /// `page`の属性を`flags`に変える
+    pub fn protect<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError>
+    where
+        for<'a> OffsetPageTable<'a>: Mapper<S>,
+    {
+        if !Self::is_user_range(page.start_address(), S::SIZE) {
+            return Err(MapError::InvalidRange);
+        }
+        let owned = self
+            .flags(page.start_address())
+            .ok_or(MapError::NotMapped)?
+            & OWNED;
+        let mut flags = (flags - OWNED) | owned | PageTableFlags::PRESENT;
+        flags |= PageTableFlags::USER_ACCESSIBLE;
+        if S::SIZE != Size4KiB::SIZE {
+            flags |= PageTableFlags::HUGE_PAGE;
+        }
+        let flush = without_interrupts(|| unsafe { self.mapper().update_flags(page, flags) })?;
+        flush.flush();
+        Ok(())
+    }
+
+    /// `[start, start + size)`がユーザー空間に収まり、全てリング3からアクセスできるか
+    /// `write`がtrueなら書き込みもできるか調べる
+    pub fn is_accessible(&mut self, start: VirtAddr, size: u64, write: bool) -> bool {
+        let end = match start.as_u64().checked_add(size) {
+            Some(end) if start.as_u64() >= USER_SPACE_START && end <= USER_SPACE_END => end,
+            _ => return false,
+        };
+        let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if write {
+            required |= PageTableFlags::WRITABLE;
+        }
+        let mut page = start.align_down(PAGE_SIZE);
+        while page.as_u64() < end {
+            if !self
+                .flags(page)
+                .is_some_and(|flags| flags.contains(required))
+            {
+                return false;
+            }
+            page += PAGE_SIZE;
+        }
+        true
+    }
+
+    /// `addr`にマップされている物理アドレスを返す
+    pub fn translate(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper().translate_addr(addr)
+    }
+
+    /// `addr`を含むページの属性を返す
+    pub fn flags(&mut self, addr: VirtAddr) -> Option<PageTableFlags> {
+        match self.mapper().translate(addr) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        }
+    }
+
+    /// まだ使っていない`size`バイトの領域を選ぶ
+    pub fn reserve(&mut self, size: u64) -> Option<VirtAddr> {
+        let start = self.mmap_next;
+        let end = start.checked_add(size.checked_next_multiple_of(PAGE_SIZE)?)?;
+        if end > USER_SPACE_END {
+            return None;
+        }
+        self.mmap_next = end;
+        Some(VirtAddr::new(start))
+    }
+
+    /// `addr`から`data`を書き込む
+    /// 読み込んでいないアドレス空間にプログラムを置くのに使う
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), MapError> {
+        let mut written = 0;
+        while written < data.len() {
+            let addr = addr + written as u64;
+            let phys = self.translate(addr).ok_or(MapError::NotMapped)?;
+            let len = (data.len() - written).min((PAGE_SIZE - addr.as_u64() % PAGE_SIZE) as usize);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[written..].as_ptr(),
+                    phys_to_virt(phys).as_mut_ptr(),
+                    len,
+                );
+            }
+            written += len;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let table = self.table();
+        for (i, entry) in table.iter().enumerate() {
+            if is_user_pml4_index(i) && !entry.is_unused() {
+                free_table(entry.frame().unwrap(), 3);
+            }
+        }
+        free_frame(self.pml4.start_address(), PAGE_SIZE);
+    }
+}
+
+/// PML4の`index`番のエントリがユーザー空間のものか
+fn is_user_pml4_index(index: usize) -> bool {
+    let start = (USER_SPACE_START >> 39) as usize;
+    let end = ((USER_SPACE_END - 1) >> 39) as usize;
+    (start..=end).contains(&index)
+}
+
+/// `S`の大きさのページに使うバディアロケータの次数
+fn order<S: PageSize>() -> usize {
+    (S::SIZE / PAGE_SIZE).trailing_zeros() as usize
+}
+
+/// `S`の大きさの物理フレームを確保する
+fn allocate_frame<S: PageSize>() -> Option<PhysFrame<S>> {
+    let frame = if S::SIZE == Size4KiB::SIZE {
+        PAGE_FRAME_MANAGER.lock().allocate()?
+    } else {
+        buddy::allocate(order::<S>())?
+    };
+    Some(PhysFrame::from_start_address(frame.start_address()).unwrap())
+}
+
+/// `allocate_frame`で確保した`size`バイトのフレームを解放する
+fn free_frame(addr: PhysAddr, size: u64) {
+    let frame = PhysFrame::containing_address(addr);
+    match size {
+        Size4KiB::SIZE => PAGE_FRAME_MANAGER.lock().free(frame),
+        Size2MiB::SIZE => buddy::free(frame, order::<Size2MiB>()),
+        Size1GiB::SIZE => buddy::free(frame, order::<Size1GiB>()),
+        _ => unreachable!("invalid page size: {:#x}", size),
+    }
+}
+
+/// `level`段目のページテーブルと、その下のテーブルや確保したフレームを解放する
+fn free_table(table: PhysFrame, level: usize) {
+    let entries = unsafe { &*phys_to_virt(table.start_address()).as_ptr::<PageTable>() };
+    for entry in entries.iter().filter(|entry| !entry.is_unused()) {
+        if level > 1 && !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            free_table(PhysFrame::containing_address(entry.addr()), level - 1);
+        } else if entry.flags().contains(OWNED) {
+            // 1段目は4KiB、2段目は2MiB、3段目は1GiBのページ
+            free_frame(entry.addr(), PAGE_SIZE << (9 * (level - 1)));
+        }
+    }
+    free_frame(table.start_address(), PAGE_SIZE);
+}
@@ -1,7 +1,75 @@
-use linked_list_allocator::LockedHeap;
+use crate::memory::{self, PAGE_SIZE};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::NonNull,
+};
+use kani2_common::boot::KERNEL_BASE;
+use linked_list_allocator::{Heap, LockedHeap};
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+
+/// ヒープを伸ばせる仮想アドレスの上限
+/// `.bss`にある最初のヒープの直後から、カーネルに割り当てた1GiBの終わりまでを使う
+const HEAP_LIMIT: u64 = KERNEL_BASE + 0x4000_0000;
+
+/// 一度に伸ばす最小の大きさ
+const MIN_GROW_SIZE: u64 = 0x10_0000;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: KernelAllocator = KernelAllocator(LockedHeap::empty());
+
+/// ヒープのロックを持ったままプリエンプションされないよう、割り込みを禁止して確保する
+/// 空きが足りなければ、新しいフレームをマップしてヒープを伸ばす
+struct KernelAllocator(LockedHeap);
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| {
+            let mut heap = self.0.lock();
+            heap.allocate_first_fit(layout)
+                .or_else(|_| {
+                    grow(&mut heap, layout);
+                    heap.allocate_first_fit(layout)
+                })
+                .map_or(core::ptr::null_mut(), |ptr| ptr.as_ptr())
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| {
+            self.0
+                .lock()
+                .deallocate(NonNull::new_unchecked(ptr), layout)
+        })
+    }
+}
+
+/// `layout`を確保できるよう、ヒープの終端の後ろに新しいフレームをマップして伸ばす
+/// フレームが足りなければ、マップできた分だけ伸ばす
+fn grow(heap: &mut Heap, layout: Layout) {
+    let top = heap.top() as u64;
+    // 境界を合わせるために空ける分も見込んでおく
+    let size = ((layout.size() + layout.align()) as u64)
+        .max(MIN_GROW_SIZE)
+        .next_multiple_of(PAGE_SIZE)
+        .min(HEAP_LIMIT.saturating_sub(top));
+    if size == 0 {
+        return;
+    }
+
+    let start = Page::containing_address(VirtAddr::new(top));
+    let pages = Page::range(start, start + size / PAGE_SIZE);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mapped = memory::map_kernel_pages(pages, flags);
+    if mapped != 0 {
+        unsafe {
+            heap.extend((mapped * PAGE_SIZE) as usize);
+        }
+    }
+}
 
 extern "C" {
     static __kernel_heap: u8;
@@ -13,6 +81,6 @@ pub fn init() {
         let kernel_heap = &__kernel_heap as *const u8 as usize;
         let kernel_heap_end = &__kernel_heap_end as *const u8 as usize;
         let length = kernel_heap_end - kernel_heap;
-        ALLOCATOR.lock().init(kernel_heap as *mut u8, length);
+        ALLOCATOR.0.lock().init(kernel_heap as *mut u8, length);
     }
 }
@@ -0,0 +1,189 @@
+use crate::{
+    memory::{phys_to_virt, PAGE_FRAME_MANAGER, PAGE_SIZE},
+    sync::IrqSpinLock,
+};
+use x86_64::{
+    structures::paging::{PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+/// 扱う最大の次数(4KiB << 18 = 1GiB)
+pub const MAX_ORDER: usize = 18;
+
+/// 2MiBのブロックの次数
+pub const ORDER_2MIB: usize = 9;
+
+/// `PageFrameManager`から一度に取ってくるブロックの次数
+/// これ以上の次数まで結合したブロックは`PageFrameManager`に返す
+const REFILL_ORDER: usize = ORDER_2MIB;
+
+/// 物理的に連続した領域を確保するためのバディアロケータ
+/// `PAGE_FRAME_MANAGER`と同じく、割り込みを禁止して取る
+pub static BUDDY_ALLOCATOR: IrqSpinLock<BuddyAllocator> = IrqSpinLock::new(BuddyAllocator::new());
+
+/// バディシステムによる物理メモリアロケータ
+///
+/// 空きブロックは次数ごとの単方向リストで管理し、リストの次のブロックの物理アドレスは
+/// 空きブロック自身の先頭8バイトに書いておく。
+/// 物理アドレス0は予約されていて確保されることがないので、リストの終端に使う。
+pub struct BuddyAllocator {
+    /// 次数ごとの空きリストの先頭
+    free_lists: [u64; MAX_ORDER + 1],
+    /// 次数ごとの空きブロックの数
+    free_counts: [usize; MAX_ORDER + 1],
+}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        Self {
+            free_lists: [0; MAX_ORDER + 1],
+            free_counts: [0; MAX_ORDER + 1],
+        }
+    }
+
+    /// `2^order`個の物理的に連続したフレームを確保する
+    /// 先頭のフレームは`2^order`フレーム境界に揃っている
+    pub fn allocate(&mut self, order: usize) -> Option<PhysFrame> {
+        assert!(order <= MAX_ORDER, "invalid order: {}", order);
+
+        let mut current = match (order..=MAX_ORDER).find(|&o| self.free_lists[o] != 0) {
+            Some(o) => o,
+            None => self.refill(order)?,
+        };
+        let block = self.pop(current);
+
+        // 大きいブロックを半分にしていき、後ろ半分を空きリストに戻す
+        while current > order {
+            current -= 1;
+            self.push(current, block + (PAGE_SIZE << current));
+        }
+
+        Some(PhysFrame::containing_address(PhysAddr::new(block)))
+    }
+
+    /// `allocate`で確保したブロックを解放し、バディが空いていれば結合する
+    pub fn free(&mut self, frame: PhysFrame, order: usize) {
+        assert!(order <= MAX_ORDER, "invalid order: {}", order);
+
+        let mut block = frame.start_address().as_u64();
+        let mut order = order;
+        assert!(
+            block % (PAGE_SIZE << order) == 0,
+            "misaligned block: {:?}",
+            frame
+        );
+
+        loop {
+            if order >= REFILL_ORDER {
+                let start = PhysFrame::containing_address(PhysAddr::new(block));
+                let end =
+                    PhysFrame::containing_address(PhysAddr::new(block + (PAGE_SIZE << order)));
+                PAGE_FRAME_MANAGER
+                    .lock()
+                    .free_contiguous(PhysFrame::range(start, end));
+                return;
+            }
+
+            let buddy = block ^ (PAGE_SIZE << order);
+            if !self.remove(order, buddy) {
+                self.push(order, block);
+                return;
+            }
+            block = block.min(buddy);
+            order += 1;
+        }
+    }
+
+    /// 次数ごとの空きブロックの数を返す
+    pub fn free_counts(&self) -> [usize; MAX_ORDER + 1] {
+        self.free_counts
+    }
+
+    /// 空きブロックの合計フレーム数を返す
+    pub fn free_frames(&self) -> usize {
+        self.free_counts
+            .iter()
+            .enumerate()
+            .map(|(order, count)| count << order)
+            .sum()
+    }
+
+    /// `PageFrameManager`からブロックを取ってきて空きリストに加え、その次数を返す
+    fn refill(&mut self, order: usize) -> Option<usize> {
+        let mut manager = PAGE_FRAME_MANAGER.lock();
+        let refill_order = order.max(REFILL_ORDER);
+        let (order, range) = match manager.allocate_aligned(1 << refill_order, 1 << refill_order) {
+            Some(range) => (refill_order, range),
+            // 2MiBの連続領域が無くても、要求された大きさなら取れるかもしれない
+            None => (order, manager.allocate_aligned(1 << order, 1 << order)?),
+        };
+        self.push(order, range.start.start_address().as_u64());
+        Some(order)
+    }
+
+    fn push(&mut self, order: usize, block: u64) {
+        unsafe {
+            set_next(block, self.free_lists[order]);
+        }
+        self.free_lists[order] = block;
+        self.free_counts[order] += 1;
+    }
+
+    fn pop(&mut self, order: usize) -> u64 {
+        let block = self.free_lists[order];
+        assert!(block != 0);
+        self.free_lists[order] = unsafe { next(block) };
+        self.free_counts[order] -= 1;
+        block
+    }
+
+    /// 空きリストから`block`を探して取り除く
+    /// 見つからなければfalseを返す
+    fn remove(&mut self, order: usize, block: u64) -> bool {
+        let mut prev = 0;
+        let mut current = self.free_lists[order];
+        while current != 0 {
+            let following = unsafe { next(current) };
+            if current == block {
+                if prev == 0 {
+                    self.free_lists[order] = following;
+                } else {
+                    unsafe {
+                        set_next(prev, following);
+                    }
+                }
+                self.free_counts[order] -= 1;
+                return true;
+            }
+            prev = current;
+            current = following;
+        }
+        false
+    }
+}
+
+/// 空きブロックに書いてある次の空きブロックの物理アドレスを読む
+unsafe fn next(block: u64) -> u64 {
+    *phys_to_virt(PhysAddr::new(block)).as_ptr::<u64>()
+}
+
+/// 空きブロックに次の空きブロックの物理アドレスを書く
+unsafe fn set_next(block: u64, next: u64) {
+    *phys_to_virt(PhysAddr::new(block)).as_mut_ptr::<u64>() = next;
+}
+
+/// `size`バイトを確保するのに必要な最小の次数を返す
+pub fn order_for_size(size: u64) -> usize {
+    let frames = size.div_ceil(PAGE_SIZE).max(1);
+    frames.next_power_of_two().trailing_zeros() as usize
+}
+
+/// バディアロケータから`2^order`フレームを確保する
+pub fn allocate(order: usize) -> Option<PhysFrame<Size4KiB>> {
+    BUDDY_ALLOCATOR.lock().allocate(order)
+}
+
+/// バディアロケータに`2^order`フレームを返す
+pub fn free(frame: PhysFrame<Size4KiB>, order: usize) {
+    BUDDY_ALLOCATOR.lock().free(frame, order);
+}
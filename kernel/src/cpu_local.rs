@@ -0,0 +1,118 @@
+use crate::{
+    memory::{self, phys_to_virt},
+    smp,
+};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use x86_64::{registers::model_specific::GsBase, VirtAddr};
+
+/// CPUごとにコピーを持つ変数を定義する
+///
+/// 変数は`.cpu_local`セクションに置かれ、これは各CPUの領域にコピーする元になる
+/// `get`で実行中のCPUのコピーを参照するので、書き換えるには`Cell`やアトミック型を使う
+#[macro_export]
+macro_rules! cpu_local {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;) => {
+        $(#[$attr])*
+        #[link_section = ".cpu_local"]
+        $vis static $name: $crate::cpu_local::CpuLocal<$ty> =
+            $crate::cpu_local::CpuLocal::new($init);
+    };
+}
+
+extern "C" {
+    static __cpu_local: u8;
+    static __cpu_local_size: u8;
+    static mut __bsp_cpu_local: u8;
+}
+
+/// 各CPUの領域の先頭に置き、アセンブリからGS相対の固定のオフセットで読み書きする値を入れる
+/// 0番には領域自身のアドレスを入れておき、GS:0から読めば実行中のCPUの領域が分かる
+#[used]
+#[link_section = ".cpu_local_head"]
+static CPU_LOCAL_HEAD: [usize; 3] = [0; 3];
+
+/// SYSCALLの入口で切り替えるカーネルスタックの終端を置くオフセット
+pub const HEAD_SYSCALL_STACK: usize = 8;
+/// SYSCALLの入口でユーザーのスタックポインタを退避するオフセット
+pub const HEAD_USER_STACK: usize = 16;
+
+/// 各CPUの領域のアドレス
+static CPU_AREAS: [AtomicUsize; smp::MAX_CPUS] = [const { AtomicUsize::new(0) }; smp::MAX_CPUS];
+
+/// CPUごとにコピーを持つ変数
+/// `cpu_local!`で定義する
+#[repr(transparent)]
+pub struct CpuLocal<T>(T);
+
+// コピーは実行中のCPUからしか触らない前提なので、Tに関わらず共有してよい
+unsafe impl<T> Sync for CpuLocal<T> {}
+
+impl<T> CpuLocal<T> {
+    pub const fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    /// `.cpu_local`セクションの先頭からのオフセット
+    fn offset(&'static self) -> usize {
+        self as *const Self as usize - unsafe { &__cpu_local as *const u8 as usize }
+    }
+
+    /// 実行中のCPUのコピーを返す
+    /// タスクが別のCPUに移ると別のCPUのコピーになるので、割り込み禁止中に使う
+    pub fn get(&'static self) -> &'static T {
+        let base: usize;
+        unsafe {
+            asm!(
+                "mov {}, gs:[0]",
+                out(reg) base,
+                options(nostack, readonly, preserves_flags),
+            );
+            &*((base + self.offset()) as *const T)
+        }
+    }
+
+    /// `cpu`番のCPUのコピーを返す
+    pub fn get_for(&'static self, cpu: usize) -> &'static T {
+        let base = CPU_AREAS[cpu].load(Ordering::Acquire);
+        assert!(base != 0, "CPU {} has no per-CPU area", cpu);
+        unsafe { &*((base + self.offset()) as *const T) }
+    }
+}
+
+/// 実行中のCPUで、SYSCALLで入ってきたときに使うカーネルスタックを設定する
+pub fn set_syscall_stack(stack_end: VirtAddr) {
+    unsafe {
+        asm!(
+            "mov gs:[{offset}], {}",
+            in(reg) stack_end.as_u64(),
+            offset = const HEAD_SYSCALL_STACK,
+            options(nostack, preserves_flags),
+        );
+    }
+}
+
+/// 実行中のCPUの領域を用意し、GSベースに設定する
+/// BSPはリンカスクリプトで確保した領域を、APはページフレームを使う
+/// GDTをロードするとGSベースが消えるので、`gdt::init`の後に呼ぶ
+pub fn init(cpu: usize) {
+    unsafe {
+        let size = &__cpu_local_size as *const u8 as usize;
+        let area: *mut u8 = if cpu == 0 {
+            core::ptr::addr_of_mut!(__bsp_cpu_local)
+        } else {
+            let frames = memory::PAGE_FRAME_MANAGER
+                .lock()
+                .allocate_contiguous(size.div_ceil(memory::PAGE_SIZE as usize))
+                .expect("no memory for a per-CPU area");
+            phys_to_virt(frames.start.start_address()).as_mut_ptr()
+        };
+
+        core::ptr::copy_nonoverlapping(&__cpu_local as *const u8, area, size);
+        *(area as *mut usize) = area as usize;
+        CPU_AREAS[cpu].store(area as usize, Ordering::Release);
+        GsBase::write(VirtAddr::from_ptr(area));
+    }
+}
@@ -0,0 +1,232 @@
+use crate::{gdt, interrupt::TrapFrame, ipi, println, task, uart, user_access};
+use core::arch::global_asm;
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, PageFaultErrorCode, SelectorErrorCode},
+    VirtAddr,
+};
+
+const DEBUG: u64 = 1;
+const NON_MASKABLE_INTERRUPT: u64 = 2;
+const BREAKPOINT: u64 = 3;
+const DOUBLE_FAULT: u64 = 8;
+const INVALID_TSS: u64 = 10;
+const SEGMENT_NOT_PRESENT: u64 = 11;
+const STACK_SEGMENT_FAULT: u64 = 12;
+const GENERAL_PROTECTION_FAULT: u64 = 13;
+const PAGE_FAULT: u64 = 14;
+
+/// 例外の名前
+const EXCEPTION_NAMES: [&str; 32] = [
+    "DIVIDE ERROR (#DE)",
+    "DEBUG (#DB)",
+    "NON-MASKABLE INTERRUPT",
+    "BREAKPOINT (#BP)",
+    "OVERFLOW (#OF)",
+    "BOUND RANGE EXCEEDED (#BR)",
+    "INVALID OPCODE (#UD)",
+    "DEVICE NOT AVAILABLE (#NM)",
+    "DOUBLE FAULT (#DF)",
+    "COPROCESSOR SEGMENT OVERRUN",
+    "INVALID TSS (#TS)",
+    "SEGMENT NOT PRESENT (#NP)",
+    "STACK SEGMENT FAULT (#SS)",
+    "GENERAL PROTECTION FAULT (#GP)",
+    "PAGE FAULT (#PF)",
+    "RESERVED",
+    "x87 FLOATING POINT (#MF)",
+    "ALIGNMENT CHECK (#AC)",
+    "MACHINE CHECK (#MC)",
+    "SIMD FLOATING POINT (#XM)",
+    "VIRTUALIZATION (#VE)",
+    "CONTROL PROTECTION (#CP)",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "HYPERVISOR INJECTION",
+    "VMM COMMUNICATION (#VC)",
+    "SECURITY (#SX)",
+    "RESERVED",
+];
+
+/// エラーコードを積まない例外の入口
+/// ISTを使う例外は`paranoid_interrupt_common`に飛ばす
+macro_rules! exception_stub {
+    ($name:ident, $vector:literal) => {
+        exception_stub!($name, $vector, interrupt_common);
+    };
+    ($name:ident, $vector:literal, $common:ident) => {
+        global_asm!(
+            concat!(".global ", stringify!($name)),
+            concat!(stringify!($name), ":"),
+            "push 0",
+            concat!("push ", stringify!($vector)),
+            concat!("jmp ", stringify!($common)),
+        );
+        extern "C" {
+            fn $name();
+        }
+    };
+}
+
+/// CPUがエラーコードを積む例外の入口
+macro_rules! exception_stub_with_error_code {
+    ($name:ident, $vector:literal) => {
+        exception_stub_with_error_code!($name, $vector, interrupt_common);
+    };
+    ($name:ident, $vector:literal, $common:ident) => {
+        global_asm!(
+            concat!(".global ", stringify!($name)),
+            concat!(stringify!($name), ":"),
+            concat!("push ", stringify!($vector)),
+            concat!("jmp ", stringify!($common)),
+        );
+        extern "C" {
+            fn $name();
+        }
+    };
+}
+
+exception_stub!(divide_error_stub, 0);
+exception_stub!(debug_stub, 1);
+exception_stub!(non_maskable_interrupt_stub, 2, paranoid_interrupt_common);
+exception_stub!(breakpoint_stub, 3);
+exception_stub!(overflow_stub, 4);
+exception_stub!(bound_range_exceeded_stub, 5);
+exception_stub!(invalid_opcode_stub, 6);
+exception_stub!(device_not_available_stub, 7);
+exception_stub_with_error_code!(double_fault_stub, 8, paranoid_interrupt_common);
+exception_stub_with_error_code!(invalid_tss_stub, 10);
+exception_stub_with_error_code!(segment_not_present_stub, 11);
+exception_stub_with_error_code!(stack_segment_fault_stub, 12);
+exception_stub_with_error_code!(general_protection_fault_stub, 13);
+exception_stub_with_error_code!(page_fault_stub, 14);
+exception_stub!(x87_floating_point_stub, 16);
+exception_stub_with_error_code!(alignment_check_stub, 17);
+exception_stub!(machine_check_stub, 18, paranoid_interrupt_common);
+exception_stub!(simd_floating_point_stub, 19);
+exception_stub!(virtualization_stub, 20);
+exception_stub_with_error_code!(vmm_communication_exception_stub, 29);
+exception_stub_with_error_code!(security_exception_stub, 30);
+
+fn stub_addr(stub: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(stub as usize as u64)
+}
+
+/// IDTに全ての例外の入口を登録する
+pub unsafe fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error
+        .set_handler_addr(stub_addr(divide_error_stub));
+    idt.debug.set_handler_addr(stub_addr(debug_stub));
+    idt.non_maskable_interrupt
+        .set_handler_addr(stub_addr(non_maskable_interrupt_stub))
+        .set_stack_index(gdt::NMI_IST_INDEX);
+    idt.breakpoint.set_handler_addr(stub_addr(breakpoint_stub));
+    idt.overflow.set_handler_addr(stub_addr(overflow_stub));
+    idt.bound_range_exceeded
+        .set_handler_addr(stub_addr(bound_range_exceeded_stub));
+    idt.invalid_opcode
+        .set_handler_addr(stub_addr(invalid_opcode_stub));
+    idt.device_not_available
+        .set_handler_addr(stub_addr(device_not_available_stub));
+    idt.double_fault
+        .set_handler_addr(stub_addr(double_fault_stub))
+        .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    idt.invalid_tss
+        .set_handler_addr(stub_addr(invalid_tss_stub));
+    idt.segment_not_present
+        .set_handler_addr(stub_addr(segment_not_present_stub));
+    idt.stack_segment_fault
+        .set_handler_addr(stub_addr(stack_segment_fault_stub));
+    idt.general_protection_fault
+        .set_handler_addr(stub_addr(general_protection_fault_stub));
+    idt.page_fault.set_handler_addr(stub_addr(page_fault_stub));
+    idt.x87_floating_point
+        .set_handler_addr(stub_addr(x87_floating_point_stub));
+    idt.alignment_check
+        .set_handler_addr(stub_addr(alignment_check_stub));
+    idt.machine_check
+        .set_handler_addr(stub_addr(machine_check_stub))
+        .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+    idt.simd_floating_point
+        .set_handler_addr(stub_addr(simd_floating_point_stub));
+    idt.virtualization
+        .set_handler_addr(stub_addr(virtualization_stub));
+    idt.vmm_communication_exception
+        .set_handler_addr(stub_addr(vmm_communication_exception_stub));
+    idt.security_exception
+        .set_handler_addr(stub_addr(security_exception_stub));
+}
+
+/// 例外を処理する
+/// 回復できない例外ではレジスタを全て表示してからpanicする
+pub fn handle(frame: &mut TrapFrame) {
+    let name = EXCEPTION_NAMES[frame.vector as usize];
+    match frame.vector {
+        NON_MASKABLE_INTERRUPT => {
+            ipi::handle_stop_nmi();
+            // NMIはロックを持っている間にも入ってくるので、ロックを取らずに書く
+            uart::write_unlocked(format_args!(
+                "EXCEPTION: {}\r\n{:#?}\r\n",
+                name, frame.stack_frame
+            ));
+        }
+        BREAKPOINT | DEBUG => {
+            println!("EXCEPTION: {}\n{:#?}", name, frame.stack_frame);
+        }
+        PAGE_FAULT => {
+            // ユーザー空間へのコピーで起きたなら、コピーを失敗させて続ける
+            if !frame.is_user_mode() {
+                let rip = frame.stack_frame.instruction_pointer;
+                if let Some(fixup) = user_access::search_fixup(rip) {
+                    frame.stack_frame.instruction_pointer = fixup;
+                    return;
+                }
+            }
+            println!(
+                "EXCEPTION: {}\naccessed address: {:?}\nerror code: {:?}",
+                name,
+                Cr2::read(),
+                PageFaultErrorCode::from_bits_truncate(frame.error_code)
+            );
+            fatal(name, frame);
+        }
+        DOUBLE_FAULT => {
+            println!("EXCEPTION: {}", name);
+            // ガードページへのアクセスでは例外のフレームを積めず、ダブルフォールトになる
+            let addr = Cr2::read();
+            if task::is_stack_guard(addr) {
+                println!("kernel stack overflow: accessed address: {:?}", addr);
+            }
+            fatal(name, frame);
+        }
+        INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT => {
+            println!("EXCEPTION: {}", name);
+            if frame.error_code != 0 {
+                println!(
+                    "error code: {:?}",
+                    SelectorErrorCode::new_truncate(frame.error_code)
+                );
+            }
+            fatal(name, frame);
+        }
+        _ => {
+            println!("EXCEPTION: {}", name);
+            fatal(name, frame);
+        }
+    }
+}
+
+/// カーネルで起きた例外ならpanicし、ユーザータスクで起きた例外ならそのタスクを終了する
+fn fatal(name: &str, frame: &TrapFrame) -> ! {
+    println!("{:#?}", frame.stack_frame);
+    frame.dump();
+    if frame.is_user_mode() {
+        println!("[warn]user task {:?} killed", task::current_tid());
+        task::exit(task::EXIT_FAULT);
+    }
+    panic!("EXCEPTION: {}", name);
+}
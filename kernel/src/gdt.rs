@@ -1,31 +1,141 @@
+use crate::smp;
+use alloc::{boxed::Box, vec};
+use core::sync::atomic::{AtomicPtr, Ordering};
 use lazy_static::lazy_static;
-use spin::Mutex;
 use x86_64::{
-    registers::segmentation::{Segment, SegmentSelector, CS, DS},
-    structures::gdt::{Descriptor, GlobalDescriptorTable},
-    PrivilegeLevel,
+    instructions::tables::load_tss,
+    registers::segmentation::{Segment, SegmentSelector, CS, DS, ES, FS, GS, SS},
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable},
+        tss::TaskStateSegment,
+    },
+    VirtAddr,
 };
 
+/// ダブルフォルトで使うISTの番号
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// NMIで使うISTの番号
+pub const NMI_IST_INDEX: u16 = 1;
+/// マシンチェックで使うISTの番号
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// ISTのスタック1つあたりのサイズ
+const IST_STACK_SIZE: usize = 0x1000 * 5;
+
+#[repr(C, align(16))]
+struct Stack([u8; IST_STACK_SIZE]);
+
+static mut DOUBLE_FAULT_STACK: Stack = Stack([0; IST_STACK_SIZE]);
+static mut NMI_STACK: Stack = Stack([0; IST_STACK_SIZE]);
+static mut MACHINE_CHECK_STACK: Stack = Stack([0; IST_STACK_SIZE]);
+
+/// TSSはRSP0を後から書き換えるのでstatic mutで持つ
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// CPUごとのTSS(BSPは`TSS`、APは起動時にヒープに確保したもの)
+static CPU_TSS: [AtomicPtr<TaskStateSegment>; smp::MAX_CPUS] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; smp::MAX_CPUS];
+
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code = gdt.add_entry(Descriptor::kernel_code_segment());
-        let data = gdt.add_entry(Descriptor::kernel_data_segment());
-        gdt.add_entry(Descriptor::user_code_segment());
-        gdt.add_entry(Descriptor::user_data_segment());
-        (gdt, Selectors { code, data })
-    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = new_gdt(unsafe { init_tss() });
+}
+
+/// `tss`を使うGDTを作る
+/// セグメントの並びはどのCPUでも同じにする
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data = gdt.add_entry(Descriptor::kernel_data_segment());
+    // SYSRETはSTARに書いたセレクタ+8をSS、+16をCSにするので、データ、コードの順に並べる
+    let user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code = gdt.add_entry(Descriptor::user_code_segment());
+    let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code,
+            data,
+            user_code,
+            user_data,
+            tss,
+        },
+    )
+}
+
+/// GDTのセグメントのセレクタ
+/// ユーザー用のセレクタのRPLは3になっている
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub code: SegmentSelector,
+    pub data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+/// ISTにそれぞれのスタックの終端を設定する
+unsafe fn init_tss() -> &'static TaskStateSegment {
+    fn stack_end(stack: *const Stack) -> VirtAddr {
+        VirtAddr::from_ptr(stack) + IST_STACK_SIZE
+    }
+
+    let tss = &mut *core::ptr::addr_of_mut!(TSS);
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        stack_end(core::ptr::addr_of!(DOUBLE_FAULT_STACK));
+    tss.interrupt_stack_table[NMI_IST_INDEX as usize] = stack_end(core::ptr::addr_of!(NMI_STACK));
+    tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] =
+        stack_end(core::ptr::addr_of!(MACHINE_CHECK_STACK));
+    tss
 }
 
-struct Selectors {
-    code: SegmentSelector,
-    data: SegmentSelector,
+/// GDTをロードし、セグメントレジスタとTSSを設定する
+fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
+    gdt.load();
+    unsafe {
+        CS::set_reg(selectors.code);
+        DS::set_reg(selectors.data);
+        ES::set_reg(selectors.data);
+        FS::set_reg(selectors.data);
+        GS::set_reg(selectors.data);
+        SS::set_reg(selectors.data);
+        load_tss(selectors.tss);
+    }
 }
 
 pub fn init() {
-    GDT.0.load();
+    load(&GDT.0, &GDT.1);
+    CPU_TSS[0].store(core::ptr::addr_of_mut!(TSS), Ordering::Release);
+}
+
+/// APのTSSとGDTを作ってロードする
+/// ISTのスタックもCPUごとに確保する
+pub fn init_ap(cpu: usize) {
+    let tss = Box::leak(Box::new(TaskStateSegment::new()));
+    for index in [
+        DOUBLE_FAULT_IST_INDEX,
+        NMI_IST_INDEX,
+        MACHINE_CHECK_IST_INDEX,
+    ] {
+        let stack = vec![0u8; IST_STACK_SIZE].leak();
+        tss.interrupt_stack_table[index as usize] =
+            (VirtAddr::from_ptr(stack.as_ptr()) + IST_STACK_SIZE).align_down(16u64);
+    }
+    CPU_TSS[cpu].store(tss, Ordering::Release);
+
+    let (gdt, selectors) = new_gdt(tss);
+    load(Box::leak(Box::new(gdt)), &selectors);
+}
+
+/// セグメントのセレクタを返す
+/// GDTの並びはどのCPUでも同じなので、BSPのものを返す
+pub fn selectors() -> Selectors {
+    GDT.1
+}
+
+/// 実行中のCPUで、リング3から割り込みで入ってきたときに使うスタック(RSP0)を設定する
+pub fn set_kernel_stack(stack_end: VirtAddr) {
+    let tss = CPU_TSS[smp::current_cpu()].load(Ordering::Acquire);
     unsafe {
-        CS::set_reg(GDT.1.code);
-        DS::set_reg(GDT.1.data);
+        (*tss).privilege_stack_table[0] = stack_end;
     }
 }
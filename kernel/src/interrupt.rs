@@ -1,40 +1,366 @@
-use crate::uart;
+use crate::{exception, lapic, println, task};
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use spin::RwLock;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrameValue},
+    VirtAddr,
+};
 
-use crate::println;
+/// GSベースのMSR
+const IA32_GS_BASE: u32 = 0xc000_0101;
+
+/// 外部割り込みに使う最初のベクタ番号
+const FIRST_IRQ_VECTOR: usize = 32;
+/// `allocate_vector`で割り当てる最初のベクタ番号
+/// 0x20から0x2fはISAのIRQが使う
+const FIRST_DYNAMIC_VECTOR: usize = 0x30;
+/// `allocate_vector`で割り当てるベクタの終わり
+/// 0xf0以降はLocal APICが使う
+const DYNAMIC_VECTORS_END: usize = 0xf0;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = unsafe {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.double_fault
-            .set_handler_fn(double_fault_handler)
-            .set_stack_index(0);
+        exception::set_handlers(&mut idt);
 
-        idt[36].set_handler_fn(uart::uart_handler);
+        for vector in FIRST_IRQ_VECTOR..256 {
+            idt[vector].set_handler_addr(irq_stub_addr(vector));
+        }
 
         idt
     };
 }
 
+/// 割り込みハンドラが割り込みを処理したか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqResult {
+    /// 自分のデバイスの割り込みだったので処理した
+    Handled,
+    /// 自分のデバイスの割り込みではなかった(共有された割り込み線で使う)
+    NotMine,
+}
+
+/// 割り込みハンドラ
+/// 2つ目の引数には登録時に渡したクッキーが渡される
+pub type Handler = dyn Fn(&mut TrapFrame, usize) -> IrqResult + Send + Sync;
+
+struct Registration {
+    cookie: usize,
+    handler: Box<Handler>,
+}
+
+/// ベクタ1つ分の登録状況
+struct Vector {
+    handlers: RwLock<Vec<Registration>>,
+    /// `allocate_vector`で割り当て済みか
+    allocated: AtomicBool,
+    /// ハンドラの後に自動でEOIを送るか
+    auto_eoi: AtomicBool,
+    /// 割り込みの回数
+    count: AtomicU64,
+}
+
+impl Vector {
+    const fn new() -> Self {
+        Self {
+            handlers: RwLock::new(Vec::new()),
+            allocated: AtomicBool::new(false),
+            auto_eoi: AtomicBool::new(true),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+static VECTORS: [Vector; 256] = [const { Vector::new() }; 256];
+
 pub fn init() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+/// 割り込みの入口で保存したレジスタ
+/// `interrupt_common`でスタックに積む順番と対応している
+#[repr(C)]
+#[derive(Debug)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    /// 割り込みベクタ番号
+    pub vector: u64,
+    /// CPUが積んだエラーコード(積まない割り込みでは0)
+    pub error_code: u64,
+    /// CPUが積んだ割り込みフレーム
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+impl TrapFrame {
+    /// リング3から割り込みで入ってきたか
+    pub fn is_user_mode(&self) -> bool {
+        self.stack_frame.code_segment & 3 == 3
+    }
+    /// 保存したレジスタを全て表示する
+    pub fn dump(&self) {
+        let f = &self.stack_frame;
+        crate::println!(
+            "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}",
+            self.rax,
+            self.rbx,
+            self.rcx,
+            self.rdx
+        );
+        crate::println!(
+            "RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}",
+            self.rsi,
+            self.rdi,
+            self.rbp,
+            f.stack_pointer.as_u64()
+        );
+        crate::println!(
+            "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}",
+            self.r8,
+            self.r9,
+            self.r10,
+            self.r11
+        );
+        crate::println!(
+            "R12={:016x} R13={:016x} R14={:016x} R15={:016x}",
+            self.r12,
+            self.r13,
+            self.r14,
+            self.r15
+        );
+        crate::println!(
+            "RIP={:016x} RFLAGS={:016x} CS={:04x} SS={:04x}",
+            f.instruction_pointer.as_u64(),
+            f.cpu_flags,
+            f.code_segment,
+            f.stack_segment
+        );
+        crate::println!("vector={} error code={:#x}", self.vector, self.error_code);
+    }
+}
+
+// 全ての割り込みの共通の入口
+// 各ベクタの入口でエラーコードとベクタ番号を積んでからここに飛んでくる
+// リング3から入ってきたときは、GSベースをカーネルのもの(CPUごとの領域)に入れ替える
+// 入れ替えたかどうかはrbxに覚えておき(callee-saved)、出口で元に戻す
+// ユーザー空間へのアクセスを許した状態(RFLAGS.AC)で割り込まれても、ハンドラではSMAPを効かせる
+global_asm!(
+    ".global interrupt_common",
+    "interrupt_common:",
+    "pushfq",
+    "and qword ptr [rsp], {clear_ac}",
+    "popfq",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "xor ebx, ebx",
+    "test qword ptr [rsp + 56], 3", // CS
+    "jz 2f",
+    "swapgs",
+    "mov ebx, 1",
+    "jmp 2f",
+    // ISTを使う例外(NMI、ダブルフォールト、マシンチェック)の入口
+    // カーネルがSWAPGSしてからIRETQやSYSRETQするまでの間や、SYSCALLの直後にも起きるので、
+    // CSではなくGSベースそのものがカーネルのアドレスかどうかで入れ替えるかを決める
+    ".global paranoid_interrupt_common",
+    "paranoid_interrupt_common:",
+    "pushfq",
+    "and qword ptr [rsp], {clear_ac}",
+    "popfq",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "xor ebx, ebx",
+    "mov ecx, {gs_base}",
+    "rdmsr",
+    "test edx, edx",
+    "js 2f",
+    "swapgs",
+    "mov ebx, 1",
+    "2:",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "call {dispatch}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "test ebx, ebx",
+    "jz 3f",
+    "swapgs",
+    "3:",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "add rsp, 16", // ベクタ番号とエラーコード
+    "iretq",
+    dispatch = sym interrupt_dispatch,
+    clear_ac = const !(1u64 << 18) as i64,
+    gs_base = const IA32_GS_BASE,
+);
+
+// 外部割り込みの入口
+// 1つ16バイトで、ベクタ32から255まで順に並べる
+global_asm!(
+    ".global irq_stubs",
+    ".balign 16",
+    "irq_stubs:",
+    ".set irq_stub_vector, 32",
+    ".rept 224",
+    ".balign 16",
+    "pushq $0",
+    "pushq $irq_stub_vector",
+    "jmp interrupt_common",
+    ".set irq_stub_vector, irq_stub_vector + 1",
+    ".endr",
+    options(att_syntax),
+);
+
+extern "C" {
+    static irq_stubs: u8;
+}
+
+const IRQ_STUB_SIZE: usize = 16;
+
+fn irq_stub_addr(vector: usize) -> VirtAddr {
+    let stubs = unsafe { &irq_stubs as *const u8 as u64 };
+    VirtAddr::new(stubs + ((vector - FIRST_IRQ_VECTOR) * IRQ_STUB_SIZE) as u64)
+}
+
+crate::cpu_local! {
+    /// 処理中の割り込みの入れ子の深さ
+    static IRQ_DEPTH: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// 割り込みハンドラの中で実行しているか
+pub fn in_interrupt() -> bool {
+    IRQ_DEPTH.get().load(Ordering::Relaxed) != 0
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+extern "C" fn interrupt_dispatch(frame: &mut TrapFrame) {
+    match frame.vector {
+        0..=31 => exception::handle(frame),
+        vector => handle_irq(frame, vector as usize),
+    }
 }
 
-pub fn notify_end_of_interrupt() {
-    unsafe {
-        core::ptr::write_volatile(0xFEE0_00B0 as *mut u32, 0);
+fn handle_irq(frame: &mut TrapFrame, vector: usize) {
+    let entry = &VECTORS[vector];
+    entry.count.fetch_add(1, Ordering::Relaxed);
+
+    IRQ_DEPTH.get().fetch_add(1, Ordering::Relaxed);
+    let mut handled = false;
+    for registration in entry.handlers.read().iter() {
+        if (registration.handler)(frame, registration.cookie) == IrqResult::Handled {
+            handled = true;
+        }
     }
+    if !handled {
+        println!("[warn]unhandled interrupt: vector {}", vector);
+    }
+
+    if entry.auto_eoi.load(Ordering::Relaxed) {
+        lapic::end_of_interrupt();
+    }
+    IRQ_DEPTH.get().fetch_sub(1, Ordering::Relaxed);
+
+    task::preempt_on_irq_exit();
+}
+
+/// `vector`に割り込みハンドラを登録する
+/// 同じベクタに複数のハンドラを登録すると、割り込みのたびに全てが呼ばれる
+pub fn register<F>(vector: u8, cookie: usize, handler: F)
+where
+    F: Fn(&mut TrapFrame, usize) -> IrqResult + Send + Sync + 'static,
+{
+    assert!(
+        vector as usize >= FIRST_IRQ_VECTOR,
+        "reserved vector: {}",
+        vector
+    );
+    let registration = Registration {
+        cookie,
+        handler: Box::new(handler),
+    };
+    without_interrupts(|| VECTORS[vector as usize].handlers.write().push(registration));
+}
+
+/// `vector`から`cookie`で登録したハンドラを取り除く
+pub fn unregister(vector: u8, cookie: usize) {
+    without_interrupts(|| {
+        VECTORS[vector as usize]
+            .handlers
+            .write()
+            .retain(|registration| registration.cookie != cookie)
+    });
+}
+
+/// 空いているベクタを1つ割り当てる
+pub fn allocate_vector() -> Option<u8> {
+    (FIRST_DYNAMIC_VECTOR..DYNAMIC_VECTORS_END)
+        .find(|&vector| {
+            VECTORS[vector]
+                .allocated
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        })
+        .map(|vector| vector as u8)
+}
+
+/// `allocate_vector`で割り当てたベクタを返す
+pub fn free_vector(vector: u8) {
+    VECTORS[vector as usize]
+        .allocated
+        .store(false, Ordering::Release);
+}
+
+/// ハンドラの後に自動でEOIを送るかを設定する
+pub fn set_auto_eoi(vector: u8, enabled: bool) {
+    VECTORS[vector as usize]
+        .auto_eoi
+        .store(enabled, Ordering::Relaxed);
+}
+
+/// `vector`の割り込みの回数を返す
+pub fn count(vector: u8) -> u64 {
+    VECTORS[vector as usize].count.load(Ordering::Relaxed)
 }
@@ -1,27 +1,280 @@
-#[repr(C)]
+use crate::{
+    acpi::{self, IoApicEntry, Polarity, TriggerMode},
+    memory::phys_to_virt,
+    smp,
+    sync::IrqSpinLock,
+};
+use alloc::vec::Vec;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// MADTが無い場合に使うI/O APICのアドレス
+const DEFAULT_IOAPIC_ADDRESS: u64 = 0xFEC0_0000;
+
+// メモリマップされたレジスタのオフセット
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+// IOREGSELで選択するレジスタ
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+const REG_TABLE: u32 = 0x10;
+
+/// ISAのIRQ0に割り当てるベクタ番号
+pub const T_IRQ0: u32 = 32;
+
+/// 割り込みハンドラの中からもマスクを操作するので、割り込みを禁止して取る
+static IOAPICS: IrqSpinLock<Vec<IoApic>> = IrqSpinLock::new(Vec::new());
+
+/// リダイレクションテーブルのエントリ
+#[derive(Debug, Clone, Copy)]
+pub struct RedirectionEntry(u64);
+
+impl RedirectionEntry {
+    const MASKED: u64 = 1 << 16;
+    const LEVEL_TRIGGERED: u64 = 1 << 15;
+    const ACTIVE_LOW: u64 = 1 << 13;
+
+    /// 固定配送・物理宛先モードのエントリを作る
+    pub fn new(vector: u8, apic_id: u32, polarity: Polarity, trigger_mode: TriggerMode) -> Self {
+        let mut entry = Self(vector as u64);
+        if polarity == Polarity::ActiveLow {
+            entry.0 |= Self::ACTIVE_LOW;
+        }
+        if trigger_mode == TriggerMode::Level {
+            entry.0 |= Self::LEVEL_TRIGGERED;
+        }
+        entry.set_destination(apic_id);
+        entry
+    }
+
+    pub fn vector(&self) -> u8 {
+        self.0 as u8
+    }
+
+    /// 宛先のLocal APIC IDを返す
+    pub fn destination(&self) -> u32 {
+        (self.0 >> 56) as u32
+    }
+
+    pub fn set_destination(&mut self, apic_id: u32) {
+        self.0 = (self.0 & !(0xff << 56)) | ((apic_id as u64 & 0xff) << 56);
+    }
+
+    pub fn is_masked(&self) -> bool {
+        self.0 & Self::MASKED != 0
+    }
+
+    pub fn set_masked(&mut self, masked: bool) {
+        if masked {
+            self.0 |= Self::MASKED;
+        } else {
+            self.0 &= !Self::MASKED;
+        }
+    }
+
+    pub fn polarity(&self) -> Polarity {
+        if self.0 & Self::ACTIVE_LOW != 0 {
+            Polarity::ActiveLow
+        } else {
+            Polarity::ActiveHigh
+        }
+    }
+
+    pub fn trigger_mode(&self) -> TriggerMode {
+        if self.0 & Self::LEVEL_TRIGGERED != 0 {
+            TriggerMode::Level
+        } else {
+            TriggerMode::Edge
+        }
+    }
+}
+
+/// I/O APIC 1つ分
 #[derive(Debug)]
-struct IoApic {
-    reg: u32,
-    pad: [u32; 3],
-    data: u32,
+pub struct IoApic {
+    id: u8,
+    base: VirtAddr,
+    /// 最初の入力ピンに対応するGSI
+    gsi_base: u32,
+    /// リダイレクションテーブルのエントリ数
+    redirection_count: u32,
 }
 
-const IOAPIC: *mut IoApic = 0xFEC0_0000 as *mut IoApic;
-const REG_TABLE: u32 = 0x10;
-const T_IRQ0: u32 = 32;
+impl IoApic {
+    unsafe fn new(entry: &IoApicEntry) -> Self {
+        let mut ioapic = Self {
+            id: entry.id,
+            base: phys_to_virt(PhysAddr::new(entry.address)),
+            gsi_base: entry.gsi_base,
+            redirection_count: 0,
+        };
+        ioapic.redirection_count = ((ioapic.read(REG_VERSION) >> 16) & 0xff) + 1;
+        ioapic
+    }
+
+    unsafe fn read(&self, reg: u32) -> u32 {
+        core::ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr::<u32>(), reg);
+        core::ptr::read_volatile((self.base + IOWIN).as_ptr::<u32>())
+    }
+
+    unsafe fn write(&self, reg: u32, data: u32) {
+        core::ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr::<u32>(), reg);
+        core::ptr::write_volatile((self.base + IOWIN).as_mut_ptr::<u32>(), data);
+    }
 
-pub unsafe fn write(reg: u32, data: u32) {
-    let ioapic = IoApic {
-        reg,
-        pad: [0; 3],
-        data,
-    };
-    core::ptr::write_volatile::<IoApic>(IOAPIC, ioapic);
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// ハードウェアから読んだI/O APIC IDを返す
+    pub fn hardware_id(&self) -> u8 {
+        unsafe { (self.read(REG_ID) >> 24) as u8 & 0x0f }
+    }
+
+    /// このI/O APICが`gsi`を受け持っているか
+    pub fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.redirection_count).contains(&gsi)
+    }
+
+    pub fn redirection_count(&self) -> u32 {
+        self.redirection_count
+    }
+
+    pub fn read_entry(&self, pin: u32) -> RedirectionEntry {
+        assert!(pin < self.redirection_count);
+        unsafe {
+            let low = self.read(REG_TABLE + 2 * pin) as u64;
+            let high = self.read(REG_TABLE + 2 * pin + 1) as u64;
+            RedirectionEntry(high << 32 | low)
+        }
+    }
+
+    pub fn write_entry(&self, pin: u32, entry: RedirectionEntry) {
+        assert!(pin < self.redirection_count);
+        unsafe {
+            // 書き換えの途中で割り込みが入らないよう、先にマスクしてから上位を書く
+            self.write(REG_TABLE + 2 * pin, RedirectionEntry::MASKED as u32);
+            self.write(REG_TABLE + 2 * pin + 1, (entry.0 >> 32) as u32);
+            self.write(REG_TABLE + 2 * pin, entry.0 as u32);
+        }
+    }
 }
 
-pub fn enable(irq: u32, cpunum: u32) {
-    unsafe {
-        write(REG_TABLE + 2 * irq, T_IRQ0 + irq);
-        write(REG_TABLE + 2 * irq + 1, cpunum << 24);
+/// MADTに載っている全てのI/O APICを登録し、全てのエントリをマスクする
+pub fn init() {
+    let default = [IoApicEntry {
+        id: 0,
+        address: DEFAULT_IOAPIC_ADDRESS,
+        gsi_base: 0,
+    }];
+    let entries = acpi::madt()
+        .map(|madt| madt.io_apics.as_slice())
+        .filter(|io_apics| !io_apics.is_empty())
+        .unwrap_or(&default);
+
+    let mut ioapics = IOAPICS.lock();
+    for entry in entries {
+        let ioapic = unsafe { IoApic::new(entry) };
+        for pin in 0..ioapic.redirection_count() {
+            let mut redirection = ioapic.read_entry(pin);
+            redirection.set_masked(true);
+            ioapic.write_entry(pin, redirection);
+        }
+        ioapics.push(ioapic);
     }
 }
+
+/// `gsi`を受け持つI/O APICとそのピン番号に対して`f`を呼ぶ
+fn with_gsi<R>(gsi: u32, f: impl FnOnce(&IoApic, u32) -> R) -> R {
+    let ioapics = IOAPICS.lock();
+    let ioapic = ioapics
+        .iter()
+        .find(|ioapic| ioapic.handles(gsi))
+        .unwrap_or_else(|| panic!("no I/O APIC handles GSI {}", gsi));
+    f(ioapic, gsi - ioapic.gsi_base)
+}
+
+/// `gsi`を`apic_id`のCPUの`vector`に配送するよう設定し、マスクを外す
+pub fn route(gsi: u32, vector: u8, apic_id: u32, polarity: Polarity, trigger_mode: TriggerMode) {
+    with_gsi(gsi, |ioapic, pin| {
+        ioapic.write_entry(
+            pin,
+            RedirectionEntry::new(vector, apic_id, polarity, trigger_mode),
+        )
+    });
+}
+
+/// `gsi`の宛先のCPUを変更する
+pub fn set_destination(gsi: u32, apic_id: u32) {
+    with_gsi(gsi, |ioapic, pin| {
+        let mut entry = ioapic.read_entry(pin);
+        entry.set_destination(apic_id);
+        ioapic.write_entry(pin, entry);
+    });
+}
+
+pub fn mask(gsi: u32) {
+    with_gsi(gsi, |ioapic, pin| {
+        let mut entry = ioapic.read_entry(pin);
+        entry.set_masked(true);
+        ioapic.write_entry(pin, entry);
+    });
+}
+
+pub fn unmask(gsi: u32) {
+    with_gsi(gsi, |ioapic, pin| {
+        let mut entry = ioapic.read_entry(pin);
+        entry.set_masked(false);
+        ioapic.write_entry(pin, entry);
+    });
+}
+
+/// `gsi`のリダイレクションエントリを読む
+pub fn read_entry(gsi: u32) -> RedirectionEntry {
+    with_gsi(gsi, |ioapic, pin| ioapic.read_entry(pin))
+}
+
+/// ISAのIRQをGSIに変換し、極性とトリガーモードも返す
+/// MADTのInterrupt Source Overrideがあればそれに従う
+pub fn isa_irq_to_gsi(irq: u32) -> (u32, Polarity, TriggerMode) {
+    let iso = acpi::madt().and_then(|madt| {
+        madt.overrides
+            .iter()
+            .find(|iso| iso.bus == 0 && iso.source as u32 == irq)
+    });
+    match iso {
+        Some(iso) => {
+            // ISAバスの規定はアクティブハイ・エッジトリガー
+            let polarity = match iso.polarity() {
+                Polarity::Conforming => Polarity::ActiveHigh,
+                polarity => polarity,
+            };
+            let trigger_mode = match iso.trigger_mode() {
+                TriggerMode::Conforming => TriggerMode::Edge,
+                trigger_mode => trigger_mode,
+            };
+            (iso.gsi, polarity, trigger_mode)
+        }
+        None => (irq, Polarity::ActiveHigh, TriggerMode::Edge),
+    }
+}
+
+/// ISAのIRQを`cpunum`番のCPUのベクタ`T_IRQ0 + irq`に配送する
+pub fn enable(irq: u32, cpunum: usize) {
+    assert!(smp::is_online(cpunum), "CPU {} is not online", cpunum);
+    let (gsi, polarity, trigger_mode) = isa_irq_to_gsi(irq);
+    route(
+        gsi,
+        (T_IRQ0 + irq) as u8,
+        smp::apic_id(cpunum),
+        polarity,
+        trigger_mode,
+    );
+}
+
+/// ISAのIRQの配送先を`cpunum`番のCPUに変える
+pub fn set_affinity(irq: u32, cpunum: usize) {
+    assert!(smp::is_online(cpunum), "CPU {} is not online", cpunum);
+    let (gsi, _, _) = isa_irq_to_gsi(irq);
+    set_destination(gsi, smp::apic_id(cpunum));
+}
@@ -0,0 +1,125 @@
+use crate::{
+    interrupt::{self, IrqResult},
+    lapic,
+    smp::{self, CpuMask},
+    sync::IrqSpinLock,
+};
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::{self, interrupts::without_interrupts};
+
+/// 他のCPUに実行させる関数
+struct CallRequest {
+    func: Box<dyn Fn() + Send + Sync>,
+    /// まだ実行し終わっていないCPUの数
+    pending: AtomicUsize,
+}
+
+crate::cpu_local! {
+    /// このCPUが実行する関数のキュー
+    static CALL_QUEUE: IrqSpinLock<VecDeque<Arc<CallRequest>>> =
+        IrqSpinLock::new(VecDeque::new());
+}
+
+/// panicしたCPUが他のCPUを止めているところか
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+pub fn init() {
+    interrupt::register(lapic::CALL_FUNCTION_VECTOR, 0, |_, _| {
+        run_pending_calls();
+        IrqResult::Handled
+    });
+}
+
+/// キューに溜まっている関数を実行する
+fn run_pending_calls() {
+    loop {
+        let request = match CALL_QUEUE.get().lock().pop_front() {
+            Some(request) => request,
+            None => return,
+        };
+        (request.func)();
+        request.pending.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// `cpus`に含まれる起動済みのCPUで`func`を実行する
+/// 他のCPUでは割り込みハンドラの中で実行するので、`func`は眠ってはいけない
+/// `wait`がtrueなら、全てのCPUが実行し終わるまで待つ
+pub fn call_on<F>(cpus: CpuMask, func: F, wait: bool)
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    without_interrupts(|| {
+        let current = smp::current_cpu();
+        let mut targets = CpuMask::empty();
+        for cpu in smp::online_cpus().filter(|&cpu| cpus.contains(cpu) && cpu != current) {
+            targets.insert(cpu);
+        }
+
+        let request = Arc::new(CallRequest {
+            func: Box::new(func),
+            pending: AtomicUsize::new(targets.bits().count_ones() as usize),
+        });
+        if targets != CpuMask::empty() {
+            for cpu in smp::online_cpus().filter(|&cpu| targets.contains(cpu)) {
+                CALL_QUEUE.get_for(cpu).lock().push_back(request.clone());
+            }
+
+            let others = smp::online_count() - 1;
+            if others == targets.bits().count_ones() as usize
+                && smp::online_count() == smp::cpu_count()
+            {
+                lapic::broadcast_fixed_ipi(lapic::CALL_FUNCTION_VECTOR);
+            } else {
+                for cpu in smp::online_cpus().filter(|&cpu| targets.contains(cpu)) {
+                    lapic::send_fixed_ipi(smp::apic_id(cpu), lapic::CALL_FUNCTION_VECTOR);
+                }
+            }
+        }
+
+        if cpus.contains(current) {
+            (request.func)();
+        }
+
+        if wait {
+            while request.pending.load(Ordering::Acquire) != 0 {
+                // 相手も同時にこちらを待っているかもしれないので、自分宛ての関数も実行する
+                run_pending_calls();
+                core::hint::spin_loop();
+            }
+        }
+    });
+}
+
+/// 実行中のCPU以外の起動済みのCPUで`func`を実行する
+pub fn call_on_others<F>(func: F, wait: bool)
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    without_interrupts(|| {
+        let mut cpus = CpuMask::all();
+        cpus.remove(smp::current_cpu());
+        call_on(cpus, func, wait);
+    });
+}
+
+/// 他の全てのCPUをNMIで止める
+/// panicしたときに呼ぶ
+pub fn stop_others() {
+    if STOPPING.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    if smp::online_count() > 1 {
+        lapic::broadcast_nmi();
+    }
+}
+
+/// NMIを受けたCPUが止まるべきなら止める
+pub fn handle_stop_nmi() {
+    if STOPPING.load(Ordering::Acquire) {
+        loop {
+            instructions::hlt();
+        }
+    }
+}
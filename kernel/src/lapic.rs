@@ -0,0 +1,323 @@
+use crate::{
+    interrupt::{self, IrqResult, TrapFrame},
+    memory::phys_to_virt,
+    println, smp,
+};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use x86_64::{instructions::port::Port, registers::model_specific::Msr, PhysAddr};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+/// IA32_APIC_BASEのAPICグローバル有効ビット
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+// Local APICのレジスタのオフセット
+const REG_ID: u64 = 0x20;
+const REG_VERSION: u64 = 0x30;
+const REG_TPR: u64 = 0x80;
+const REG_EOI: u64 = 0xb0;
+const REG_SVR: u64 = 0xf0;
+const REG_ESR: u64 = 0x280;
+const REG_ICR_LOW: u64 = 0x300;
+const REG_ICR_HIGH: u64 = 0x310;
+const REG_LVT_TIMER: u64 = 0x320;
+const REG_LVT_ERROR: u64 = 0x370;
+const REG_TIMER_INITIAL_COUNT: u64 = 0x380;
+const REG_TIMER_CURRENT_COUNT: u64 = 0x390;
+const REG_TIMER_DIVIDE: u64 = 0x3e0;
+
+/// SVRのAPICソフトウェア有効ビット
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+/// LVTのマスクビット
+const LVT_MASKED: u32 = 1 << 16;
+/// LVTタイマーの周期モードビット
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// タイマーの分周比を16にする
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// ICRの配送状態ビット(送信中なら1)
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+/// ICRのレベルアサート
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+/// ICRの配送モード: NMI
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+/// ICRの配送モード: INIT
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+/// ICRの配送モード: Start Up
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+/// ICRの送り先の略記: 自分以外の全CPU
+const ICR_DEST_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+/// タイマー割り込みのベクタ番号
+pub const TIMER_VECTOR: u8 = 0xf0;
+/// 再スケジュールを要求するIPIのベクタ番号
+pub const RESCHEDULE_VECTOR: u8 = 0xf1;
+/// 他のCPUに関数を実行させるIPIのベクタ番号
+pub const CALL_FUNCTION_VECTOR: u8 = 0xf2;
+/// APICエラー割り込みのベクタ番号
+pub const ERROR_VECTOR: u8 = 0xfe;
+/// スプリアス割り込みのベクタ番号
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// 周期タイマーの周波数
+pub const TIMER_HZ: u64 = 100;
+
+/// PITの入力クロックの周波数
+const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+/// キャリブレーションでPITを待たせる時間
+const CALIBRATION_MS: u64 = 10;
+/// PITのカウンタが16ビットなので、1回で待てる最大の時間
+const PIT_MAX_WAIT_US: u64 = 50_000;
+
+/// Local APICのレジスタの物理アドレス
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+/// 1ミリ秒あたりのタイマーのカウント数(分周後)
+static TIMER_TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+/// 周期タイマーの割り込み回数
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+crate::cpu_local! {
+    /// 実行中のCPUのLocal APIC ID
+    static LAPIC_ID: AtomicU32 = AtomicU32::new(0);
+}
+
+unsafe fn read(reg: u64) -> u32 {
+    let base = LAPIC_BASE.load(Ordering::Relaxed);
+    core::ptr::read_volatile(phys_to_virt(PhysAddr::new(base + reg)).as_ptr::<u32>())
+}
+
+unsafe fn write(reg: u64, value: u32) {
+    let base = LAPIC_BASE.load(Ordering::Relaxed);
+    core::ptr::write_volatile(
+        phys_to_virt(PhysAddr::new(base + reg)).as_mut_ptr::<u32>(),
+        value,
+    );
+}
+
+/// Local APICを有効にし、タイマーをキャリブレーションしてから周期タイマーを開始する
+pub fn init() {
+    unsafe {
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        let value = apic_base.read();
+        apic_base.write(value | APIC_GLOBAL_ENABLE);
+        LAPIC_BASE.store(value & 0x000f_ffff_ffff_f000, Ordering::Relaxed);
+    }
+    init_local();
+
+    interrupt::register(TIMER_VECTOR, 0, timer_handler);
+    interrupt::register(ERROR_VECTOR, 0, error_handler);
+    interrupt::register(SPURIOUS_VECTOR, 0, spurious_handler);
+    // スプリアス割り込みにはEOIを送らない
+    interrupt::set_auto_eoi(SPURIOUS_VECTOR, false);
+
+    let ticks_per_ms = calibrate_timer();
+    TIMER_TICKS_PER_MS.store(ticks_per_ms, Ordering::Relaxed);
+    println!(
+        "[info]local APIC: id {}, version {:#x}, timer {} ticks/ms",
+        id(),
+        unsafe { read(REG_VERSION) } & 0xff,
+        ticks_per_ms
+    );
+
+    start_periodic(TIMER_VECTOR, 1000 / TIMER_HZ);
+}
+
+/// 実行中のCPUのLocal APICを有効にする
+pub fn init_local() {
+    unsafe {
+        LAPIC_ID.get().store(read(REG_ID) >> 24, Ordering::Relaxed);
+        write(REG_TPR, 0);
+        write(REG_LVT_ERROR, ERROR_VECTOR as u32);
+        write(REG_ESR, 0);
+        write(REG_SVR, SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        write(REG_LVT_TIMER, LVT_MASKED);
+    }
+}
+
+/// PITのチャネル2で`us`マイクロ秒(PIT_MAX_WAIT_USまで)のカウントダウンを始める
+unsafe fn pit_start(us: u64) {
+    let mut gate = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel2 = Port::<u8>::new(0x42);
+
+    // ゲートを上げ、スピーカーは切る
+    let value = gate.read();
+    gate.write((value & !0x02) | 0x01);
+
+    // チャネル2、下位/上位バイト、モード0
+    command.write(0b1011_0000);
+    let count = (PIT_FREQUENCY_HZ * us / 1_000_000).max(1);
+    channel2.write(count as u8);
+    channel2.write((count >> 8) as u8);
+
+    // ゲートを上げ直してカウントを開始する
+    let value = gate.read();
+    gate.write(value & !0x01);
+    gate.write(value | 0x01);
+}
+
+/// `pit_start`で始めたカウントダウンが終わるまで待つ
+unsafe fn pit_wait() {
+    // PITの出力が立ち上がるまで待つ
+    while Port::<u8>::new(0x61).read() & 0x20 == 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// PITを使って`us`マイクロ秒ビジーウェイトする
+/// PITは1つしかないので、起動処理のように1つのCPUしか使わない場面で使う
+pub fn delay_us(mut us: u64) {
+    while us > 0 {
+        let wait = us.min(PIT_MAX_WAIT_US);
+        unsafe {
+            pit_start(wait);
+            pit_wait();
+        }
+        us -= wait;
+    }
+}
+
+/// PITのチャネル2を使って、1ミリ秒あたりのタイマーのカウント数を測る
+fn calibrate_timer() -> u64 {
+    unsafe {
+        pit_start(CALIBRATION_MS * 1000);
+        write(REG_TIMER_INITIAL_COUNT, u32::MAX);
+        pit_wait();
+
+        let elapsed = u32::MAX - read(REG_TIMER_CURRENT_COUNT);
+        write(REG_TIMER_INITIAL_COUNT, 0);
+        elapsed as u64 / CALIBRATION_MS
+    }
+}
+
+/// 実行中のCPUのLocal APIC IDを返す
+pub fn id() -> u32 {
+    LAPIC_ID.get().load(Ordering::Relaxed)
+}
+
+/// `apic_id`のCPUにプロセッサ間割り込みを送り、配送されるまで待つ
+/// 送り先の略記を使うときは`apic_id`は無視される
+unsafe fn send_ipi(apic_id: u32, command: u32) {
+    write(REG_ICR_HIGH, apic_id << 24);
+    write(REG_ICR_LOW, command);
+    while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// `apic_id`のCPUの`vector`に割り込みを送る
+pub fn send_fixed_ipi(apic_id: u32, vector: u8) {
+    unsafe {
+        send_ipi(apic_id, ICR_LEVEL_ASSERT | vector as u32);
+    }
+}
+
+/// 自分以外の全CPUの`vector`に割り込みを送る
+/// 起動していないCPUにも届くので、全CPUが起動してから使う
+pub fn broadcast_fixed_ipi(vector: u8) {
+    unsafe {
+        send_ipi(
+            0,
+            ICR_DEST_ALL_EXCLUDING_SELF | ICR_LEVEL_ASSERT | vector as u32,
+        );
+    }
+}
+
+/// `apic_id`のCPUにNMIを送る
+pub fn send_nmi(apic_id: u32) {
+    unsafe {
+        send_ipi(apic_id, ICR_DELIVERY_NMI | ICR_LEVEL_ASSERT);
+    }
+}
+
+/// 自分以外の全CPUにNMIを送る
+pub fn broadcast_nmi() {
+    unsafe {
+        send_ipi(
+            0,
+            ICR_DEST_ALL_EXCLUDING_SELF | ICR_DELIVERY_NMI | ICR_LEVEL_ASSERT,
+        );
+    }
+}
+
+/// `apic_id`のCPUにINIT IPIを送ってリセットする
+pub fn send_init(apic_id: u32) {
+    unsafe {
+        send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+    }
+}
+
+/// `apic_id`のCPUにStartup IPIを送り、物理アドレス`page << 12`から実行させる
+pub fn send_startup(apic_id: u32, page: u8) {
+    unsafe {
+        send_ipi(
+            apic_id,
+            ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | page as u32,
+        );
+    }
+}
+
+/// 割り込みの処理が終わったことをLocal APICに通知する
+pub fn end_of_interrupt() {
+    unsafe {
+        write(REG_EOI, 0);
+    }
+}
+
+/// `interval_ms`ミリ秒ごとに`vector`の割り込みを発生させる
+pub fn start_periodic(vector: u8, interval_ms: u64) {
+    let count = TIMER_TICKS_PER_MS.load(Ordering::Relaxed) * interval_ms;
+    unsafe {
+        write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+        write(REG_TIMER_INITIAL_COUNT, count.min(u32::MAX as u64) as u32);
+    }
+}
+
+/// `us`マイクロ秒後に一度だけ`vector`の割り込みを発生させる
+pub fn start_one_shot(vector: u8, us: u64) {
+    let count = (TIMER_TICKS_PER_MS.load(Ordering::Relaxed) * us / 1000).max(1);
+    unsafe {
+        write(REG_LVT_TIMER, vector as u32);
+        write(REG_TIMER_INITIAL_COUNT, count.min(u32::MAX as u64) as u32);
+    }
+}
+
+/// タイマーを止める
+pub fn stop_timer() {
+    unsafe {
+        write(REG_LVT_TIMER, LVT_MASKED);
+        write(REG_TIMER_INITIAL_COUNT, 0);
+    }
+}
+
+/// 周期タイマーが開始してからの割り込み回数を返す
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// 周期タイマーが開始してからの経過時間をミリ秒で返す
+/// カーネルの時計として使い、精度はタイマーの周期(1000 / TIMER_HZ ms)
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / TIMER_HZ
+}
+
+fn timer_handler(_: &mut TrapFrame, _: usize) -> IrqResult {
+    // 時計はBSPのタイマーだけで進める
+    if smp::current_cpu() == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+    IrqResult::Handled
+}
+
+fn error_handler(_: &mut TrapFrame, _: usize) -> IrqResult {
+    let esr = unsafe {
+        write(REG_ESR, 0);
+        read(REG_ESR)
+    };
+    println!("[error]local APIC error: {:#x}", esr);
+    IrqResult::Handled
+}
+
+fn spurious_handler(_: &mut TrapFrame, _: usize) -> IrqResult {
+    IrqResult::Handled
+}
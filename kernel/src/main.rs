@@ -1,21 +1,35 @@
 #![no_std]
 #![no_main]
 #![feature(alloc_error_handler)]
-#![feature(abi_x86_interrupt)]
 
 extern crate alloc;
 
+mod acpi;
+mod address_space;
 mod allocator;
+mod buddy;
+mod cpu_local;
+mod exception;
 mod gdt;
 mod interrupt;
 mod ioapic;
+mod ipi;
+mod lapic;
 mod memory;
 mod println;
+mod smp;
+mod sync;
+mod syscall;
 mod task;
 mod uart;
+mod user_access;
+#[cfg(feature = "selftest")]
+mod user_program;
+mod wait_queue;
 
 use core::{arch::asm, panic::PanicInfo};
-use kani2_common::boot::{BootInfo, MemoryMap};
+use kani2_common::boot::BootInfo;
+use x86_64::PhysAddr;
 
 extern "C" {
     static mut __kernel_stack: u8;
@@ -32,29 +46,57 @@ pub extern "sysv64" fn kernel_main(boot_info: &'static BootInfo) -> ! {
         );
     }
 
+    // ローダーから渡されたアドレスはアイデンティティマップのものなので、ストレートマップのものにする
+    let boot_info: &'static BootInfo = unsafe {
+        &*memory::phys_to_virt(PhysAddr::new(boot_info as *const BootInfo as u64)).as_ptr()
+    };
+
     // 初期化
-    init();
+    init(boot_info);
 
     println!("[info]hello kani2 kernel");
 
-    unsafe {
-        for m in boot_info.mmap().into_iter() {
-            let m = m.as_ref().unwrap();
-            println!(
-                "{:?}: 0x{:016x} - {} page",
-                m.ty, m.phys_start, m.page_count
-            );
-        }
+    for m in memory::memory_descriptors(boot_info) {
+        println!(
+            "{:?}: 0x{:016x} - {} page",
+            m.ty, m.phys_start, m.page_count
+        );
+    }
+
+    {
+        let manager = memory::PAGE_FRAME_MANAGER.lock();
+        println!(
+            "[info]free memory: {} / {} KiB",
+            manager.free_frames() * 4,
+            manager.total_frames() * 4
+        );
+    }
+
+    if let Some(madt) = acpi::madt() {
+        println!(
+            "[info]ACPI: {} processors, {} I/O APICs",
+            madt.processors.len(),
+            madt.io_apics.len()
+        );
+    }
+
+    #[cfg(feature = "selftest")]
+    {
+        let code = user_program::spawn().join();
+        println!("[info]user program exited with code {}", code);
     }
 
     loop {
+        task::yield_now();
         x86_64::instructions::hlt();
     }
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{:?}", info);
+    ipi::stop_others();
+    // 止めたCPUがUARTのロックを持っているかもしれないので、ロックを取らずに書く
+    uart::write_unlocked(format_args!("{:?}\r\n", info));
     loop {
         x86_64::instructions::hlt();
     }
@@ -65,10 +107,19 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("allocation error: {:?}", layout)
 }
 
-fn init() {
+fn init(boot_info: &BootInfo) {
     allocator::init();
     gdt::init();
+    cpu_local::init(0);
     interrupt::init();
+    memory::init(boot_info);
+    acpi::init(boot_info);
+    lapic::init();
+    ioapic::init();
     uart::init();
-    memory::init();
+    task::init();
+    syscall::init();
+    ipi::init();
+    smp::init(boot_info);
+    memory::unmap_identity();
 }
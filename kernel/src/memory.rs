@@ -1,118 +1,652 @@
-use bitmaps::Bitmap;
+use crate::{ipi, smp::CpuMask, sync::IrqSpinLock};
+use alloc::vec::Vec;
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count},
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use kani2_common::boot::{BootInfo, MemoryDescriptor, MemoryType, KERNEL_BASE, PHYS_MEMORY_OFFSET};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::{
-    registers::control::{Cr3, Cr3Flags},
-    structures::paging::{OffsetPageTable, PageTable, PageTableFlags, PhysFrame},
+    instructions::{interrupts::without_interrupts, tlb},
+    registers::{
+        control::{Cr0, Cr0Flags, Cr3, Cr3Flags, Cr4, Cr4Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        frame::PhysFrameRange, page::PageRange, page_table::PageTableEntry, FrameAllocator,
+        FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+        Size2MiB, Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
 
 extern "C" {
+    static __kernel_image: u8;
+    static __kernel_rodata: u8;
+    static __kernel_data: u8;
+    static __kernel_heap_end: u8;
     static __kernel_pagetable_pml4: u8;
-    static __kernel_pagetable_pdpt: u8;
-    static __kernel_pagetable_pd: u8;
 }
 
-pub fn init() {
+/// 4KiBページのサイズ
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// ブート中だけ残しておくアイデンティティマップの終端(PML4の0番のエントリの分)
+const IDENTITY_MAP_END: u64 = 0x80_0000_0000;
+
+/// 1MiB未満はファームウェアやリアルモードのコードが使うので管理しない
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// PML4のうち、カーネルが使う上位半分の先頭の添字
+const KERNEL_PML4_START: usize = 256;
+
+/// CPUIDのリーフ7でSMEP、SMAP、UMIPに対応しているかを表すビット
+const CPUID_7_EBX_SMEP: u32 = 1 << 7;
+const CPUID_7_EBX_SMAP: u32 = 1 << 20;
+const CPUID_7_ECX_UMIP: u32 = 1 << 2;
+
+/// SMAPを有効にしたか
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// これより多くのページを無効化するときは、TLBを全て捨てる
+const TLB_FLUSH_ALL_THRESHOLD: u64 = 32;
+
+// メモリ管理のロックは、ヒープ、`KERNEL_PAGE_TABLE_LOCK`、`buddy::BUDDY_ALLOCATOR`、
+// `PAGE_FRAME_MANAGER`の順に取る
+// ヒープを伸ばすときに後ろの3つを取るので、これらを持ったままメモリを確保してはいけない
+
+/// カーネルのページテーブルを書き換えるときに取るロック
+static KERNEL_PAGE_TABLE_LOCK: Mutex<()> = Mutex::new(());
+
+/// 物理フレームのアロケータ
+/// 割り込みハンドラの中のメモリ確保でヒープを伸ばすこともあるので、割り込みを禁止して取る
+pub static PAGE_FRAME_MANAGER: IrqSpinLock<PageFrameManager> =
+    IrqSpinLock::new(PageFrameManager::empty());
+
+pub fn init(boot_info: &BootInfo) {
     init_kernel_page_table();
-    let _ = PageFrameManager::new();
+    unsafe {
+        PAGE_FRAME_MANAGER.lock().init(boot_info);
+    }
+    map_kernel_image();
+    protect_straight_map();
+    populate_kernel_pml4();
+    enable_user_protection();
 }
 
-fn init_kernel_page_table() {
+/// APでCPUごとの設定をする
+pub fn init_ap() {
+    enable_user_protection();
+}
+
+/// CPUが対応していれば、カーネルがユーザーのページを実行したり触ったりするのを禁止する
+/// UMIPが有効なら、ユーザーはSGDTなどでカーネルのアドレスを知ることもできない
+fn enable_user_protection() {
+    let (ebx, ecx) = if __cpuid(0).eax >= 7 {
+        let leaf = __cpuid_count(7, 0);
+        (leaf.ebx, leaf.ecx)
+    } else {
+        (0, 0)
+    };
+    let mut flags = Cr4Flags::empty();
+    if ebx & CPUID_7_EBX_SMEP != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if ebx & CPUID_7_EBX_SMAP != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+    }
+    if ecx & CPUID_7_ECX_UMIP != 0 {
+        flags |= Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION;
+    }
     unsafe {
-        let frame = construct_kernel_page_table();
-        Cr3::write(frame, Cr3Flags::empty());
+        Cr4::update(|cr4| cr4.insert(flags));
     }
+    SMAP_ENABLED.store(
+        flags.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION),
+        Ordering::Relaxed,
+    );
+}
+
+/// SMAPが有効か
+/// 有効なら、ユーザー空間に触る間はSTACでRFLAGS.ACを立てる必要がある
+pub fn is_smap_enabled() -> bool {
+    SMAP_ENABLED.load(Ordering::Relaxed)
+}
+
+/// 物理アドレスをカーネルからアクセスできる仮想アドレスに変換する
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(addr.as_u64() + PHYS_MEMORY_OFFSET)
+}
+
+/// カーネルイメージかストレートマップの仮想アドレスを物理アドレスに変換する
+pub fn virt_to_phys(addr: VirtAddr) -> PhysAddr {
+    let addr = addr.as_u64();
+    if addr >= KERNEL_BASE {
+        PhysAddr::new(addr - KERNEL_BASE)
+    } else {
+        assert!(
+            addr >= PHYS_MEMORY_OFFSET,
+            "not a kernel address: {:#x}",
+            addr
+        );
+        PhysAddr::new(addr - PHYS_MEMORY_OFFSET)
+    }
+}
+
+/// ブート情報のメモリマップのディスクリプタを返す
+pub fn memory_descriptors(boot_info: &BootInfo) -> &'static [MemoryDescriptor] {
+    let mmap = boot_info.mmap();
+    unsafe {
+        core::slice::from_raw_parts(
+            phys_to_virt(PhysAddr::new(mmap.as_ptr() as u64)).as_ptr(),
+            mmap.len() as usize,
+        )
+    }
+}
+
+/// ブートサービス終了後にカーネルが自由に使ってよいメモリか
+fn is_usable(ty: MemoryType) -> bool {
+    matches!(
+        ty,
+        MemoryType::CONVENTIONAL
+            | MemoryType::BOOT_SERVICES_CODE
+            | MemoryType::BOOT_SERVICES_DATA
+            | MemoryType::LOADER_CODE
+            | MemoryType::LOADER_DATA
+    )
 }
 
-unsafe fn construct_kernel_page_table() -> PhysFrame {
-    let pml4 = (&__kernel_pagetable_pml4 as *const u8 as *mut PageTable)
-        .as_mut()
-        .unwrap();
-    let pml4_addr = &__kernel_pagetable_pml4 as *const u8 as *const PageTable;
+/// メモリディスクリプタが表す物理アドレスの範囲
+fn desc_range(desc: &MemoryDescriptor) -> Range<u64> {
+    desc.phys_start..desc.phys_start + desc.page_count * PAGE_SIZE
+}
 
-    let pdpt = (&__kernel_pagetable_pdpt as *const u8 as *mut PageTable)
-        .as_mut()
-        .unwrap();
-    let pdpt_addr = &__kernel_pagetable_pdpt as *const u8 as *const PageTable;
+/// 物理アドレスの範囲が、カーネルが自由に使ってよいメモリに収まっているか
+pub fn is_usable_range(boot_info: &BootInfo, range: Range<u64>) -> bool {
+    memory_descriptors(boot_info)
+        .iter()
+        .filter(|desc| is_usable(desc.ty))
+        .map(desc_range)
+        .any(|usable| usable.start <= range.start && range.end <= usable.end)
+}
 
-    let pds = (&__kernel_pagetable_pd as *const u8 as *mut [PageTable; 4])
-        .as_mut()
-        .unwrap();
-    let pds_addrs = &__kernel_pagetable_pd as *const u8 as *const [PageTable; 4];
+/// ローダーが作ったページテーブルからストレートマップとカーネルの部分を引き継ぎ、
+/// カーネルのページテーブルに切り替える
+/// APの起動に使うので、下位のアイデンティティマップも`unmap_identity`まで残しておく
+fn init_kernel_page_table() {
+    // NXビットと、リング0での書き込み禁止ページへの書き込みの検出を有効にする
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
 
+    let boot = unsafe { &*phys_to_virt(Cr3::read().0.start_address()).as_ptr::<PageTable>() };
+    let pml4 = unsafe { kernel_pml4() };
+    let kernel_index = usize::from(VirtAddr::new(KERNEL_BASE).p4_index());
     pml4.zero();
-    pdpt.zero();
-    for pd in pds.iter_mut() {
-        pd.zero();
+    for i in KERNEL_PML4_START..512 {
+        pml4[i] = boot[i].clone();
+        // ストレートマップを通してコードを実行することはない
+        if i != kernel_index && !pml4[i].is_unused() {
+            let flags = pml4[i].flags() | PageTableFlags::NO_EXECUTE;
+            pml4[i].set_flags(flags);
+        }
+    }
+    pml4[0] = boot[0].clone();
+    unsafe {
+        Cr3::write(kernel_p4_frame(), Cr3Flags::empty());
+    }
+}
+
+/// カーネルイメージをセクションごとの属性で4KiBページにマップし直す
+/// `.text`は読み込みと実行、`.rodata`は読み込み、それ以降は読み書きだけを許す
+fn map_kernel_image() {
+    let (image, rodata, data, end) = unsafe {
+        (
+            VirtAddr::from_ptr(&__kernel_image),
+            VirtAddr::from_ptr(&__kernel_rodata),
+            VirtAddr::from_ptr(&__kernel_data),
+            VirtAddr::from_ptr(&__kernel_heap_end),
+        )
+    };
+    let regions = [
+        (image..rodata, PageTableFlags::PRESENT),
+        (
+            rodata..data,
+            PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+        ),
+        (
+            data..end,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        ),
+    ];
+
+    // 実行中のコードもマップしているので、別のPML4の上で作ってからエントリを差し替える
+    let mut manager = PAGE_FRAME_MANAGER.lock();
+    let scratch = manager
+        .allocate()
+        .expect("no memory for the kernel page table");
+    let scratch_table = unsafe { &mut *phys_to_virt(scratch.start_address()).as_mut_ptr() };
+    let mut mapper =
+        unsafe { OffsetPageTable::new(scratch_table, VirtAddr::new(PHYS_MEMORY_OFFSET)) };
+    mapper.level_4_table().zero();
+    for (range, flags) in regions {
+        let pages = Page::<Size4KiB>::range(
+            Page::containing_address(range.start),
+            Page::containing_address(range.end),
+        );
+        for page in pages {
+            let frame = PhysFrame::containing_address(virt_to_phys(page.start_address()));
+            unsafe {
+                mapper
+                    .map_to(page, frame, flags, &mut *manager)
+                    .expect("failed to map the kernel image")
+                    .ignore();
+            }
+        }
+    }
+
+    let index = VirtAddr::new(KERNEL_BASE).p4_index();
+    unsafe {
+        kernel_pml4()[index] = mapper.level_4_table()[index].clone();
     }
+    manager.free(scratch);
+    tlb::flush_all();
+}
 
-    // PML4
-    pml4[0].set_addr(
-        PhysAddr::new(pdpt_addr as u64),
-        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+/// ストレートマップのうち、カーネルの`.text`と`.rodata`の物理ページを読み込み専用にする
+/// ローダーは2MiBページでマップしているので、その部分は4KiBページに分割する
+/// ストレートマップとアイデンティティマップはページテーブルを共有しているが、
+/// アイデンティティマップを通してカーネルイメージに書き込むことはない
+fn protect_straight_map() {
+    let (image, data) = unsafe {
+        (
+            virt_to_phys(VirtAddr::from_ptr(&__kernel_image)),
+            virt_to_phys(VirtAddr::from_ptr(&__kernel_data)),
+        )
+    };
+    let readonly = phys_to_virt(image)..phys_to_virt(data);
+    let huge_pages = Page::<Size2MiB>::range(
+        Page::containing_address(readonly.start),
+        Page::containing_address(readonly.end - 1u64) + 1,
     );
 
-    // PDPT
-    for i in 0..4 {
-        pdpt[i].set_addr(
-            PhysAddr::new(&pds[i] as *const PageTable as u64),
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+    let pml4 = unsafe { kernel_pml4() };
+    let mut manager = PAGE_FRAME_MANAGER.lock();
+    for huge_page in huge_pages {
+        let pdpt = unsafe { table_at(&pml4[huge_page.p4_index()]) };
+        let pdpt_entry = &pdpt[huge_page.p3_index()];
+        assert!(
+            !pdpt_entry.flags().contains(PageTableFlags::HUGE_PAGE),
+            "the straight map uses 1GiB pages"
         );
+        let pd = unsafe { table_at(pdpt_entry) };
+        let entry = &mut pd[huge_page.p2_index()];
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let frame = manager
+                .allocate()
+                .expect("no memory for the kernel page table");
+            let pt = unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>() };
+            let flags = entry.flags() - PageTableFlags::HUGE_PAGE;
+            for (i, pte) in pt.iter_mut().enumerate() {
+                pte.set_addr(entry.addr() + i as u64 * PAGE_SIZE, flags);
+            }
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+
+        let pt = unsafe { table_at(entry) };
+        let start =
+            Page::<Size4KiB>::containing_address(huge_page.start_address().max(readonly.start));
+        let end = Page::containing_address(
+            (huge_page.start_address() + huge_page.size()).min(readonly.end) - 1u64,
+        ) + 1;
+        for page in Page::range(start, end) {
+            let pte = &mut pt[page.p1_index()];
+            let flags = pte.flags() - PageTableFlags::WRITABLE;
+            pte.set_flags(flags);
+        }
+    }
+    tlb::flush_all();
+}
+
+/// ページテーブルのエントリが指す次の段のテーブル
+unsafe fn table_at(entry: &PageTableEntry) -> &'static mut PageTable {
+    &mut *phys_to_virt(entry.addr()).as_mut_ptr()
+}
+
+/// カーネルの部分のPML4エントリを全て埋めておく
+/// ユーザーのアドレス空間はこのエントリをコピーするので、後からカーネルに追加したマップも見える
+fn populate_kernel_pml4() {
+    let pml4 = unsafe { kernel_pml4() };
+    let mut manager = PAGE_FRAME_MANAGER.lock();
+    for entry in pml4.iter_mut().skip(KERNEL_PML4_START) {
+        if entry.is_unused() {
+            let frame = manager
+                .allocate()
+                .expect("no memory for the kernel page table");
+            unsafe {
+                (*phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()).zero();
+            }
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    }
+}
+
+/// ブート中だけ使う下位のアイデンティティマップを外す
+/// APのトランポリンが使うので、`smp::init`の後に呼ぶ
+pub fn unmap_identity() {
+    update_kernel_page_table(VirtAddr::zero()..VirtAddr::new(IDENTITY_MAP_END), |table| {
+        table.level_4_table()[0].set_unused()
+    });
+}
+
+/// カーネルのページテーブルのPML4
+unsafe fn kernel_pml4() -> &'static mut PageTable {
+    &mut *(core::ptr::addr_of!(__kernel_pagetable_pml4) as *mut PageTable)
+}
+
+/// カーネルのページテーブルを`f`で書き換え、`pages`のTLBを全てのCPUで無効化する
+/// `f`の中でマッパーが返す`MapperFlush`は無視してよい
+/// ヒープを伸ばすときにもページテーブルをロックするので、`f`の中でメモリを確保してはいけない
+pub fn update_kernel_page_table<R>(
+    pages: Range<VirtAddr>,
+    f: impl FnOnce(&mut OffsetPageTable<'static>) -> R,
+) -> R {
+    let result = without_interrupts(|| {
+        let _lock = KERNEL_PAGE_TABLE_LOCK.lock();
+        let pml4 = unsafe { kernel_pml4() };
+        let mut table = unsafe { OffsetPageTable::new(pml4, VirtAddr::new(PHYS_MEMORY_OFFSET)) };
+        f(&mut table)
+    });
+    flush_tlb(pages);
+    result
+}
+
+/// カーネルのページテーブルで、`pages`に新しいフレームを割り当ててマップする
+/// 先頭から順にマップし、フレームが足りなくなったらそこで止めて、マップできたページ数を返す
+/// まだ何もマップしていなかった場所に使うので、他のCPUのTLBを無効化しなくてよい
+/// ヒープを伸ばすときに呼ぶので、この中でメモリを確保してはいけない
+pub fn map_kernel_pages(pages: PageRange, flags: PageTableFlags) -> u64 {
+    without_interrupts(|| {
+        let _lock = KERNEL_PAGE_TABLE_LOCK.lock();
+        let pml4 = unsafe { kernel_pml4() };
+        let mut table = unsafe { OffsetPageTable::new(pml4, VirtAddr::new(PHYS_MEMORY_OFFSET)) };
+        let mut manager = PAGE_FRAME_MANAGER.lock();
+        let mut mapped = 0;
+        for page in pages {
+            let frame = match manager.allocate() {
+                Some(frame) => frame,
+                None => break,
+            };
+            match unsafe { table.map_to(page, frame, flags, &mut *manager) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    manager.free(frame);
+                    break;
+                }
+            }
+            mapped += 1;
+        }
+        mapped
+    })
+}
+
+/// カーネルのページテーブルで`pages`のマップを外し、マップしていたフレームを解放する
+/// マップされていないページは無視する
+/// フレームは全てのCPUのTLBを無効化してから解放する
+pub fn unmap_kernel_pages(pages: PageRange) {
+    // ページテーブルのロック中はメモリを確保できないので、先に場所を空けておく
+    let mut frames = Vec::with_capacity(pages.count());
+    let range = pages.start.start_address()..pages.end.start_address();
+    update_kernel_page_table(range, |table| {
+        for page in pages {
+            if let Ok((frame, _)) = table.unmap(page) {
+                frames.push(frame);
+            }
+        }
+    });
+    let mut manager = PAGE_FRAME_MANAGER.lock();
+    for frame in frames {
+        manager.free(frame);
     }
+}
 
-    // PD identity mapping 4GiB (2MiB * 512 * 4)
-    const PAGE_SIZE: u64 = 2097152; // 2MiB
-    for i_pd in 0..4 {
-        for i in 0..512 {
-            pds[i_pd][i].set_addr(
-                PhysAddr::new(PAGE_SIZE * (i + i_pd * 512) as u64),
-                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::HUGE_PAGE,
-            );
+/// `pages`のTLBを全てのCPUで無効化する
+/// カーネルのページテーブルは全てのCPUで共有しているので、書き換えたら必ず呼ぶ
+pub fn flush_tlb(pages: Range<VirtAddr>) {
+    ipi::call_on(CpuMask::all(), move || flush_local_tlb(pages.clone()), true);
+}
+
+/// 実行中のCPUで`pages`のTLBを無効化する
+fn flush_local_tlb(pages: Range<VirtAddr>) {
+    let count = (pages.end - pages.start).div_ceil(PAGE_SIZE);
+    if count > TLB_FLUSH_ALL_THRESHOLD {
+        tlb::flush_all();
+    } else {
+        for i in 0..count {
+            tlb::flush(pages.start + i * PAGE_SIZE);
         }
     }
+}
 
-    PhysFrame::containing_address(PhysAddr::new(pml4_addr as u64))
+/// カーネルのページテーブルのPML4の物理フレーム
+pub fn kernel_p4_frame() -> PhysFrame {
+    let pml4 = VirtAddr::from_ptr(core::ptr::addr_of!(__kernel_pagetable_pml4));
+    PhysFrame::containing_address(virt_to_phys(pml4))
 }
 
-/// ビット1つにつき2MiBで、2MiB * 512 * 4 = 4GiB
-#[derive(Debug)]
+/// 4KiBの物理フレームをビットマップで管理する
+/// ビットが立っているフレームは使用中
 pub struct PageFrameManager {
-    frame_map: [Bitmap<512>; 4],
+    bitmap: &'static mut [u64],
+    /// 管理しているフレームの数
+    frame_count: usize,
+    /// 空いているフレームの数
+    free_count: usize,
+    /// 次に空きフレームを探し始める位置
+    next: usize,
 }
 
 impl PageFrameManager {
-    pub fn new() -> Self {
-        // ページテーブルを読む
-        let (phys_frame, _) = Cr3::read();
-        let pml4 = unsafe {
-            (phys_frame.start_address().as_u64() as *const PageTable)
-                .as_ref()
-                .unwrap()
-        };
-        let pdpt = unsafe {
-            (pml4[0].addr().as_u64() as *const PageTable)
-                .as_ref()
-                .unwrap()
-        };
-        let pds = unsafe {
-            (pdpt[0].addr().as_u64() as *const [PageTable; 4])
-                .as_ref()
-                .unwrap()
-        };
-
-        let mut frame_map = [Bitmap::new(); 4];
-        for i in 0..4 {
-            let pd = &pds[i];
-            let bitmap = &mut frame_map[i];
-            for j in 0..512 {
-                let flags = pd[j].flags();
-                if flags.contains(PageTableFlags::DIRTY) {
-                    bitmap.set(j, true);
+    /// 何も管理していない状態で生成する
+    pub const fn empty() -> Self {
+        Self {
+            bitmap: &mut [],
+            frame_count: 0,
+            free_count: 0,
+            next: 0,
+        }
+    }
+
+    /// UEFIのメモリマップからビットマップを構築する
+    ///
+    /// カーネルイメージ(ヒープ含む)、ブート情報、メモリマップ、ビットマップ自身は予約する
+    unsafe fn init(&mut self, boot_info: &BootInfo) {
+        let mmap = *boot_info.mmap();
+        let descs = memory_descriptors(boot_info);
+
+        let mem_end = descs
+            .iter()
+            .filter(|desc| is_usable(desc.ty))
+            .map(|desc| desc_range(desc).end)
+            .max()
+            .unwrap_or(0);
+        let frame_count = (mem_end / PAGE_SIZE) as usize;
+        let bitmap_len = frame_count.div_ceil(64);
+        let bitmap_size = (bitmap_len * core::mem::size_of::<u64>()) as u64;
+
+        let kernel_start = virt_to_phys(VirtAddr::from_ptr(&__kernel_image)).as_u64();
+        let kernel_end = virt_to_phys(VirtAddr::from_ptr(&__kernel_heap_end)).as_u64();
+        let boot_info_start = virt_to_phys(VirtAddr::from_ptr(boot_info)).as_u64();
+        let mmap_start = mmap.as_ptr() as u64;
+        let reserved = [
+            0..LOW_MEMORY_END,
+            kernel_start..kernel_end,
+            boot_info_start..boot_info_start + core::mem::size_of::<BootInfo>() as u64,
+            mmap_start..mmap_start + mmap.len() * core::mem::size_of::<MemoryDescriptor>() as u64,
+        ];
+        let overlaps = |a: &Range<u64>, b: &Range<u64>| a.start < b.end && b.start < a.end;
+
+        // ビットマップ自身を置く場所を探す
+        let bitmap_start = descs
+            .iter()
+            .filter(|desc| is_usable(desc.ty))
+            .map(desc_range)
+            .find(|range| {
+                range.end <= mem_end
+                    && range.end - range.start >= bitmap_size
+                    && !reserved.iter().any(|r| overlaps(r, range))
+            })
+            .expect("no memory for the page frame bitmap")
+            .start;
+
+        self.bitmap = core::slice::from_raw_parts_mut(
+            phys_to_virt(PhysAddr::new(bitmap_start)).as_mut_ptr(),
+            bitmap_len,
+        );
+        self.bitmap.fill(u64::MAX);
+        self.frame_count = frame_count;
+
+        for desc in descs.iter().filter(|desc| is_usable(desc.ty)) {
+            self.mark_range(desc_range(desc), false);
+        }
+        for range in reserved {
+            self.mark_range(range, true);
+        }
+        self.mark_range(bitmap_start..bitmap_start + bitmap_size, true);
+
+        self.free_count = self.bitmap.iter().map(|w| w.count_zeros() as usize).sum();
+        self.next = 0;
+    }
+
+    /// 物理アドレスの範囲に含まれるフレームの使用状態をまとめて変更する
+    fn mark_range(&mut self, range: Range<u64>, used: bool) {
+        let start = (range.start / PAGE_SIZE) as usize;
+        let end = range.end.div_ceil(PAGE_SIZE) as usize;
+        for i in start..end.min(self.frame_count) {
+            self.set_used(i, used);
+        }
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set_used(&mut self, index: usize, used: bool) {
+        if used {
+            self.bitmap[index / 64] |= 1 << (index % 64);
+        } else {
+            self.bitmap[index / 64] &= !(1 << (index % 64));
+        }
+    }
+
+    /// `from`以降で最初の空きフレームの番号を返す
+    fn find_free(&self, from: usize) -> Option<usize> {
+        let mut i = from;
+        while i < self.frame_count {
+            if self.bitmap[i / 64] == u64::MAX {
+                i = (i / 64 + 1) * 64;
+                continue;
+            }
+            if !self.is_used(i) {
+                return Some(i);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    fn frame_index(&self, frame: PhysFrame) -> usize {
+        let index = (frame.start_address().as_u64() / PAGE_SIZE) as usize;
+        assert!(index < self.frame_count, "frame out of range: {:?}", frame);
+        index
+    }
+
+    fn frame_at(index: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(index as u64 * PAGE_SIZE))
+    }
+
+    /// 4KiBの物理フレームを1つ確保する
+    pub fn allocate(&mut self) -> Option<PhysFrame> {
+        let index = self.find_free(self.next).or_else(|| self.find_free(0))?;
+        self.set_used(index, true);
+        self.free_count -= 1;
+        self.next = index + 1;
+        Some(Self::frame_at(index))
+    }
+
+    /// 物理的に連続した`count`個のフレームを確保する
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrameRange> {
+        self.allocate_aligned(count, 1)
+    }
+
+    /// 物理的に連続した`count`個のフレームを、先頭が`align`フレーム境界になるように確保する
+    /// `align`は2の冪でなければならない
+    pub fn allocate_aligned(&mut self, count: usize, align: usize) -> Option<PhysFrameRange> {
+        assert!(align.is_power_of_two());
+        if count == 0 || count > self.free_count {
+            return None;
+        }
+
+        let align_up = |i: usize| (i + align - 1) & !(align - 1);
+        let mut start = align_up(self.find_free(0)?);
+        while start + count <= self.frame_count {
+            match (start..start + count).find(|&i| self.is_used(i)) {
+                Some(used) => start = align_up(self.find_free(used + 1)?),
+                None => {
+                    for i in start..start + count {
+                        self.set_used(i, true);
+                    }
+                    self.free_count -= count;
+                    return Some(PhysFrame::range(
+                        Self::frame_at(start),
+                        Self::frame_at(start + count),
+                    ));
                 }
             }
         }
+        None
+    }
+
+    /// 確保していたフレームを解放する
+    pub fn free(&mut self, frame: PhysFrame) {
+        let index = self.frame_index(frame);
+        assert!(self.is_used(index), "double free: {:?}", frame);
+        self.set_used(index, false);
+        self.free_count += 1;
+        if index < self.next {
+            self.next = index;
+        }
+    }
+
+    /// `allocate_contiguous`で確保したフレームをまとめて解放する
+    pub fn free_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.free(frame);
+        }
+    }
+
+    /// 空いているフレームの数を返す
+    pub fn free_frames(&self) -> usize {
+        self.free_count
+    }
+
+    /// 管理しているフレームの数を返す
+    pub fn total_frames(&self) -> usize {
+        self.frame_count
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for PageFrameManager {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate()
+    }
+}
 
-        Self { frame_map }
+impl FrameDeallocator<Size4KiB> for PageFrameManager {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.free(frame);
     }
 }
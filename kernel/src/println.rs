@@ -11,9 +11,6 @@ macro_rules! println {
 
 pub fn _print(args: core::fmt::Arguments) {
     use crate::uart::UART;
-    use x86_64::instructions::interrupts;
-    interrupts::without_interrupts(|| {
-        use core::fmt::Write;
-        UART.lock().write_fmt(args).unwrap();
-    });
+    use core::fmt::Write;
+    UART.lock().write_fmt(args).unwrap();
 }
@@ -0,0 +1,363 @@
+use crate::{
+    acpi, cpu_local, gdt, interrupt, lapic, memory,
+    memory::phys_to_virt,
+    println, syscall,
+    task::{self, KernelStack},
+};
+use alloc::vec::Vec;
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use kani2_common::boot::BootInfo;
+use x86_64::{instructions, registers::control::Cr3, PhysAddr};
+
+/// 扱うCPUの最大数
+pub const MAX_CPUS: usize = 64;
+
+/// APのトランポリンを置く物理アドレス(1MiB未満でページ境界)
+const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+/// INIT IPIを送ってから待つ時間
+const INIT_DELAY_US: u64 = 10_000;
+/// Startup IPIを送ってから待つ時間
+const STARTUP_DELAY_US: u64 = 200;
+/// APが起動するのを待つ最大の時間
+const ONLINE_TIMEOUT_US: u64 = 100_000;
+
+/// CPUの情報
+/// 添字がCPU番号で、0番がBSP
+#[derive(Debug)]
+struct Cpu {
+    apic_id: u32,
+    online: AtomicBool,
+}
+
+static CPUS: spin::Once<Vec<Cpu>> = spin::Once::new();
+
+crate::cpu_local! {
+    /// 実行中のCPUの番号
+    static CPU_NUMBER: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// 起動を待っているAPの番号(待っていなければ`usize::MAX`)
+/// APは入口でこれを自分の番号から戻してから動き出す
+/// BSPが待つのを諦めた後に遅れて動き出したAPは、戻せないのでそこで止まる
+static STARTING_CPU: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+// APはトランポリンのコピーをリアルモードで実行し、
+// 保護モードを経てロングモードに入ってから`ap_trampoline_entry`を呼ぶ
+// トランポリンはTRAMPOLINE_ADDRにコピーされるので、絶対アドレスはそこからのオフセットで求める
+global_asm!(
+    ".pushsection .rodata.ap_trampoline, \"a\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".global ap_trampoline_cr3",
+    ".global ap_trampoline_stack",
+    ".global ap_trampoline_entry",
+    ".global ap_trampoline_cpu",
+    ".code16",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    "xorw %ax, %ax",
+    "movw %ax, %ds",
+    "lgdtl ({base} + ap_trampoline_gdtr - ap_trampoline_start)",
+    "movl %cr0, %eax",
+    "orl $1, %eax",
+    "movl %eax, %cr0",
+    "ljmpl $0x08, ${base} + ap_trampoline_32 - ap_trampoline_start",
+    ".code32",
+    "ap_trampoline_32:",
+    "movw $0x10, %ax",
+    "movw %ax, %ds",
+    "movw %ax, %es",
+    "movw %ax, %ss",
+    // PAEを有効にし、BSPと同じページテーブルを使う
+    "movl %cr4, %eax",
+    "orl $0x20, %eax",
+    "movl %eax, %cr4",
+    "movl ({base} + ap_trampoline_cr3 - ap_trampoline_start), %eax",
+    "movl %eax, %cr3",
+    // EFER.LMEとNXEを立ててから、書き込み保護とページングを有効にする
+    "movl $0xc0000080, %ecx",
+    "rdmsr",
+    "orl $0x900, %eax",
+    "wrmsr",
+    "movl %cr0, %eax",
+    "orl $0x80010000, %eax",
+    "movl %eax, %cr0",
+    "ljmpl $0x18, ${base} + ap_trampoline_64 - ap_trampoline_start",
+    ".code64",
+    "ap_trampoline_64:",
+    "movw $0x10, %ax",
+    "movw %ax, %ds",
+    "movw %ax, %es",
+    "movw %ax, %ss",
+    "movq ({base} + ap_trampoline_stack - ap_trampoline_start), %rsp",
+    "movq ({base} + ap_trampoline_cpu - ap_trampoline_start), %rdi",
+    "movq ({base} + ap_trampoline_entry - ap_trampoline_start), %rax",
+    "callq *%rax",
+    "2:",
+    "hlt",
+    "jmp 2b",
+    ".balign 8",
+    "ap_trampoline_gdt:",
+    ".quad 0",
+    ".quad 0x00cf9a000000ffff", // 32ビットコード
+    ".quad 0x00cf92000000ffff", // データ
+    ".quad 0x00af9a000000ffff", // 64ビットコード
+    "ap_trampoline_gdtr:",
+    ".word 4 * 8 - 1",
+    ".long {base} + ap_trampoline_gdt - ap_trampoline_start",
+    ".balign 8",
+    "ap_trampoline_cr3: .quad 0",
+    "ap_trampoline_stack: .quad 0",
+    "ap_trampoline_entry: .quad 0",
+    "ap_trampoline_cpu: .quad 0",
+    "ap_trampoline_end:",
+    ".popsection",
+    base = const TRAMPOLINE_ADDR,
+    options(att_syntax),
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_cpu: u8;
+}
+
+/// トランポリンのコピーの中で`symbol`に対応する場所を返す
+unsafe fn trampoline_param(symbol: &u8) -> *mut u64 {
+    let offset = symbol as *const u8 as u64 - &ap_trampoline_start as *const u8 as u64;
+    phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR + offset)).as_mut_ptr()
+}
+
+/// MADTからCPUを列挙し、APをINIT-SIPI-SIPIで起動する
+pub fn init(boot_info: &BootInfo) {
+    let bsp_id = lapic::id();
+    let mut cpus = alloc::vec![Cpu {
+        apic_id: bsp_id,
+        online: AtomicBool::new(true),
+    }];
+    if let Some(madt) = acpi::madt() {
+        for processor in madt.processors.iter() {
+            if !processor.enabled || processor.apic_id == bsp_id {
+                continue;
+            }
+            if cpus.len() == MAX_CPUS {
+                println!("[warn]SMP: ignoring CPUs beyond {}", MAX_CPUS);
+                break;
+            }
+            cpus.push(Cpu {
+                apic_id: processor.apic_id,
+                online: AtomicBool::new(false),
+            });
+        }
+    }
+    let cpus = CPUS.call_once(|| cpus);
+
+    if cpus.len() == 1 {
+        return;
+    }
+
+    unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let size = &ap_trampoline_end as *const u8 as u64 - start as u64;
+        if !memory::is_usable_range(boot_info, TRAMPOLINE_ADDR..TRAMPOLINE_ADDR + size) {
+            println!(
+                "[warn]SMP: {:#x} is not usable for the AP trampoline",
+                TRAMPOLINE_ADDR
+            );
+            return;
+        }
+        core::ptr::copy_nonoverlapping(
+            start,
+            phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR)).as_mut_ptr(),
+            size as usize,
+        );
+        *trampoline_param(&ap_trampoline_cr3) = Cr3::read().0.start_address().as_u64();
+        *trampoline_param(&ap_trampoline_entry) =
+            ap_main as extern "C" fn(u64) -> ! as usize as u64;
+    }
+
+    for (cpu, info) in cpus.iter().enumerate().skip(1) {
+        if !start_ap(cpu, info) {
+            println!(
+                "[warn]SMP: CPU {} (APIC ID {}) did not start",
+                cpu, info.apic_id
+            );
+        }
+    }
+    println!("[info]SMP: {} / {} CPUs online", online_count(), cpus.len());
+}
+
+/// APを1つ起動し、オンラインになるまで待つ
+/// APのスタックはタスクと同じくガードページ付きのカーネルスタックを使う
+fn start_ap(cpu: usize, info: &Cpu) -> bool {
+    let stack = KernelStack::new();
+    unsafe {
+        *trampoline_param(&ap_trampoline_stack) = stack.top().as_u64();
+        *trampoline_param(&ap_trampoline_cpu) = cpu as u64;
+    }
+    STARTING_CPU.store(cpu, Ordering::Release);
+
+    let page = (TRAMPOLINE_ADDR >> 12) as u8;
+    lapic::send_init(info.apic_id);
+    lapic::delay_us(INIT_DELAY_US);
+    for _ in 0..2 {
+        lapic::send_startup(info.apic_id, page);
+        lapic::delay_us(STARTUP_DELAY_US);
+        if info.online.load(Ordering::Acquire) {
+            break;
+        }
+    }
+
+    let mut waited = 0;
+    while waited < ONLINE_TIMEOUT_US && !info.online.load(Ordering::Acquire) {
+        lapic::delay_us(1000);
+        waited += 1000;
+    }
+
+    if STARTING_CPU
+        .compare_exchange(cpu, usize::MAX, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        // APは入口を通ったので、オンラインになるまで待つ
+        while !info.online.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+        // APはこのスタックを使い続ける
+        core::mem::forget(stack);
+        return true;
+    }
+
+    // 次のAPのスタックやCPU番号で遅れて動き出さないよう、リセットしたままにしておく
+    // リセットしたAPはもうスタックを使わないので、解放してよい
+    lapic::send_init(info.apic_id);
+    false
+}
+
+/// トランポリンから呼ばれるAPの入口
+extern "C" fn ap_main(cpu: u64) -> ! {
+    let cpu = cpu as usize;
+    if STARTING_CPU
+        .compare_exchange(cpu, usize::MAX, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        // BSPが待つのを諦めたので、INITでリセットされるまで止まっておく
+        loop {
+            instructions::hlt();
+        }
+    }
+    gdt::init_ap(cpu);
+    cpu_local::init(cpu);
+    CPU_NUMBER.get().store(cpu, Ordering::Relaxed);
+    interrupt::init();
+    memory::init_ap();
+    lapic::init_local();
+    syscall::init();
+    task::init_ap();
+
+    let info = &cpus()[cpu];
+    info.online.store(true, Ordering::Release);
+    println!("[info]SMP: CPU {} (APIC ID {}) online", cpu, info.apic_id);
+
+    lapic::start_periodic(lapic::TIMER_VECTOR, 1000 / lapic::TIMER_HZ);
+    task::idle_loop();
+}
+
+fn cpus() -> &'static [Cpu] {
+    CPUS.get().map_or(&[], |cpus| cpus.as_slice())
+}
+
+/// 認識しているCPUの数
+pub fn cpu_count() -> usize {
+    cpus().len().max(1)
+}
+
+/// 起動しているCPUの数
+pub fn online_count() -> usize {
+    cpus()
+        .iter()
+        .filter(|cpu| cpu.online.load(Ordering::Acquire))
+        .count()
+        .max(1)
+}
+
+/// `cpu`番のCPUが起動しているか
+pub fn is_online(cpu: usize) -> bool {
+    cpus()
+        .get(cpu)
+        .map_or(cpu == 0, |info| info.online.load(Ordering::Acquire))
+}
+
+/// 起動しているCPUの番号を順に返す
+pub fn online_cpus() -> impl Iterator<Item = usize> {
+    (0..cpu_count()).filter(|&cpu| is_online(cpu))
+}
+
+/// `cpu`番のCPUのLocal APIC ID
+pub fn apic_id(cpu: usize) -> u32 {
+    match cpus().get(cpu) {
+        Some(info) => info.apic_id,
+        None if cpu == 0 => lapic::id(),
+        None => panic!("no such CPU: {}", cpu),
+    }
+}
+
+/// 実行中のCPUの番号
+pub fn current_cpu() -> usize {
+    CPU_NUMBER.get().load(Ordering::Relaxed)
+}
+
+/// CPUの集合(ビットiがi番のCPUを表す)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuMask(u64);
+
+impl CpuMask {
+    /// すべてのCPU
+    pub const fn all() -> Self {
+        Self(u64::MAX)
+    }
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// `cpu`番のCPUだけ
+    pub const fn single(cpu: usize) -> Self {
+        assert!(cpu < MAX_CPUS);
+        Self(1 << cpu)
+    }
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(&self, cpu: usize) -> bool {
+        cpu < MAX_CPUS && self.0 & (1 << cpu) != 0
+    }
+
+    pub fn insert(&mut self, cpu: usize) {
+        assert!(cpu < MAX_CPUS, "invalid CPU number: {}", cpu);
+        self.0 |= 1 << cpu;
+    }
+
+    pub fn remove(&mut self, cpu: usize) {
+        assert!(cpu < MAX_CPUS, "invalid CPU number: {}", cpu);
+        self.0 &= !(1 << cpu);
+    }
+
+    /// 起動しているCPUを1つも含まないか
+    pub fn is_offline(&self) -> bool {
+        !online_cpus().any(|cpu| self.contains(cpu))
+    }
+}
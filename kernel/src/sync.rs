@@ -0,0 +1,442 @@
+use crate::{
+    task::{self, Tid},
+    wait_queue::{TimedOut, WaitQueue},
+};
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use x86_64::instructions::interrupts;
+
+/// 保持している間は割り込みを禁止するスピンロック
+/// 割り込みハンドラとタスクの両方から触るデータに使う
+#[derive(Debug)]
+pub struct IrqSpinLock<T> {
+    inner: spin::Mutex<T>,
+}
+
+pub struct IrqSpinLockGuard<'a, T> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    /// ロックを取る前に割り込みが許可されていたか
+    interrupts_enabled: bool,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    /// 割り込みを禁止してロックを取る
+    /// ガードを破棄すると、割り込みフラグを元に戻す
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqSpinLockGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts_enabled,
+        }
+    }
+}
+
+impl<T> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+        }
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// 取れるまで眠って待つミューテックス
+/// 保持しているタスクを記録しておく
+/// 割り込みハンドラからは使えない
+pub struct Mutex<T> {
+    owner: IrqSpinLock<Option<Tid>>,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mutex")
+            .field("owner", &self.owner())
+            .finish_non_exhaustive()
+    }
+}
+
+/// ロックを取ったタスクだけが持てるので、他のタスクには渡せない
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            owner: IrqSpinLock::new(None),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// ロックを保持しているタスクを返す
+    pub fn owner(&self) -> Option<Tid> {
+        *self.owner.lock()
+    }
+
+    fn acquire(&self, tid: Tid) -> bool {
+        let mut owner = self.owner.lock();
+        if owner.is_some() {
+            return false;
+        }
+        *owner = Some(tid);
+        true
+    }
+
+    fn guard(&self) -> MutexGuard<'_, T> {
+        MutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// ロックを取れるまで眠る
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let tid = task::current_tid();
+        assert!(self.owner() != Some(tid), "mutex locked twice by {:?}", tid);
+        self.waiters.wait(|| self.acquire(tid));
+        self.guard()
+    }
+
+    /// ロックを取れるまで`timeout_ms`ミリ秒まで眠る
+    pub fn lock_timeout(&self, timeout_ms: u64) -> Result<MutexGuard<'_, T>, TimedOut> {
+        let tid = task::current_tid();
+        self.waiters
+            .wait_timeout(Some(timeout_ms), || self.acquire(tid))?;
+        Ok(self.guard())
+    }
+
+    /// 眠らずにロックを取る
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if self.acquire(task::current_tid()) {
+            Some(self.guard())
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        *self.mutex.owner.lock() = None;
+        self.mutex.waiters.wake_one();
+    }
+}
+
+/// 計数セマフォ
+/// `release`は割り込みハンドラからも呼べる
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// 眠らずにカウントを1つ減らす
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// カウントを1つ減らせるまで眠る
+    pub fn acquire(&self) {
+        self.waiters.wait(|| self.try_acquire());
+    }
+
+    /// カウントを1つ減らせるまで`timeout_ms`ミリ秒まで眠る
+    pub fn acquire_timeout(&self, timeout_ms: u64) -> Result<(), TimedOut> {
+        self.waiters
+            .wait_timeout(Some(timeout_ms), || self.try_acquire())
+    }
+
+    /// カウントを1つ増やし、待っているタスクを起こす
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+/// 条件変数
+/// `Mutex`と組み合わせて使う
+pub struct Condvar {
+    /// 通知のたびに増える
+    sequence: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            sequence: AtomicU64::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// ロックを手放して通知を待ち、もう一度ロックを取って返す
+    /// 通知が無くても起きることがあるので、呼び出し側で条件を調べ直すこと
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let (guard, _) = self.wait_inner(guard, None);
+        guard
+    }
+
+    /// `wait`と同じだが、`timeout_ms`ミリ秒経つと通知が無くても戻る
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout_ms: u64,
+    ) -> (MutexGuard<'a, T>, Result<(), TimedOut>) {
+        self.wait_inner(guard, Some(timeout_ms))
+    }
+
+    fn wait_inner<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout_ms: Option<u64>,
+    ) -> (MutexGuard<'a, T>, Result<(), TimedOut>) {
+        let mutex = guard.mutex;
+        // ロックを手放す前に読んでおけば、その後の通知を取りこぼさない
+        let sequence = self.sequence.load(Ordering::Acquire);
+        drop(guard);
+        let result = self.waiters.wait_timeout(timeout_ms, || {
+            self.sequence.load(Ordering::Acquire) != sequence
+        });
+        (mutex.lock(), result)
+    }
+
+    /// 待っているタスクを1つ起こす
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// 待っているタスクをすべて起こす
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+}
+
+/// 読み込みは複数、書き込みは1つだけが保持できるロック
+/// 書き込みを待っているタスクがいる間は、新しい読み込みを待たせる
+pub struct RwLock<T> {
+    state: IrqSpinLock<RwState>,
+    readers: WaitQueue,
+    writers: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+struct RwState {
+    /// 読み込みのロックを保持している数
+    readers: usize,
+    /// 書き込みのロックを保持しているタスク
+    writer: Option<Tid>,
+    /// 書き込みのロックを待っている数
+    waiting_writers: usize,
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: IrqSpinLock::new(RwState {
+                readers: 0,
+                writer: None,
+                waiting_writers: 0,
+            }),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// 書き込みのロックを保持しているタスクを返す
+    pub fn writer(&self) -> Option<Tid> {
+        self.state.lock().writer
+    }
+
+    fn acquire_read(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.writer.is_some() || state.waiting_writers > 0 {
+            return false;
+        }
+        state.readers += 1;
+        true
+    }
+
+    fn acquire_write(&self, tid: Tid) -> bool {
+        let mut state = self.state.lock();
+        if state.writer.is_some() || state.readers > 0 {
+            return false;
+        }
+        state.writer = Some(tid);
+        true
+    }
+
+    /// 読み込みのロックを取れるまで眠る
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.readers.wait(|| self.acquire_read());
+        RwLockReadGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// 読み込みのロックを取れるまで`timeout_ms`ミリ秒まで眠る
+    pub fn read_timeout(&self, timeout_ms: u64) -> Result<RwLockReadGuard<'_, T>, TimedOut> {
+        self.readers
+            .wait_timeout(Some(timeout_ms), || self.acquire_read())?;
+        Ok(RwLockReadGuard {
+            lock: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// 書き込みのロックを取れるまで眠る
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let _ = self.write_inner(None);
+        RwLockWriteGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// 書き込みのロックを取れるまで`timeout_ms`ミリ秒まで眠る
+    pub fn write_timeout(&self, timeout_ms: u64) -> Result<RwLockWriteGuard<'_, T>, TimedOut> {
+        self.write_inner(Some(timeout_ms))?;
+        Ok(RwLockWriteGuard {
+            lock: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    fn write_inner(&self, timeout_ms: Option<u64>) -> Result<(), TimedOut> {
+        let tid = task::current_tid();
+        self.state.lock().waiting_writers += 1;
+        let result = self
+            .writers
+            .wait_timeout(timeout_ms, || self.acquire_write(tid));
+        self.state.lock().waiting_writers -= 1;
+        if result.is_err() {
+            // 待たせていた読み込みを再開させる
+            self.readers.wake_all();
+        }
+        result
+    }
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let readers = {
+            let mut state = self.lock.state.lock();
+            state.readers -= 1;
+            state.readers
+        };
+        if readers == 0 {
+            self.lock.writers.wake_one();
+        }
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.lock().writer = None;
+        self.lock.writers.wake_one();
+        self.lock.readers.wake_all();
+    }
+}
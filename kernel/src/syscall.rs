@@ -0,0 +1,320 @@
+use crate::{
+    address_space::{AddressSpace, MapError},
+    cpu_local, gdt, lapic,
+    memory::PAGE_SIZE,
+    task::{self, CpuStats},
+    uart::{self, UART},
+    user_access::{self, BadAddress, UserPtr},
+};
+use core::arch::global_asm;
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::paging::PageTableFlags,
+    VirtAddr,
+};
+
+/// タスクを終了する: exit(code)
+pub const SYS_EXIT: u64 = 0;
+/// UARTに書き込む: write(fd, buf, len) -> 書き込んだバイト数
+pub const SYS_WRITE: u64 = 1;
+/// CPUを譲る: yield()
+pub const SYS_YIELD: u64 = 2;
+/// 眠る: sleep(ms)
+pub const SYS_SLEEP: u64 = 3;
+/// TIDを返す: getpid() -> tid
+pub const SYS_GETPID: u64 = 4;
+/// メモリを割り当てる: mmap(addr, len, prot) -> addr
+pub const SYS_MMAP: u64 = 5;
+/// UARTから読み込む: read(fd, buf, len) -> 読み込んだバイト数
+pub const SYS_READ: u64 = 6;
+/// 起動からのミリ秒を`*ptr`に書き込む: uptime(ptr)
+pub const SYS_UPTIME: u64 = 7;
+/// CPUごとのスケジューラの統計を`*ptr`に書き込む: cpu_stats(cpu, ptr)
+pub const SYS_CPU_STATS: u64 = 8;
+
+/// 標準入力のファイルディスクリプタ
+const STDIN: u64 = 0;
+/// 標準出力のファイルディスクリプタ
+const STDOUT: u64 = 1;
+/// 標準エラー出力のファイルディスクリプタ
+const STDERR: u64 = 2;
+
+/// mmapのprotのビット
+const PROT_WRITE: u64 = 0x2;
+const PROT_EXEC: u64 = 0x4;
+
+/// 1回のwriteで書き込む最大のバイト数
+const WRITE_MAX: u64 = 0x10000;
+
+/// システムコールが失敗した理由
+/// 戻り値としては符号を反転してraxに入れる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Errno {
+    /// 不正なファイルディスクリプタ(EBADF)
+    BadFileDescriptor = 9,
+    /// メモリが足りない(ENOMEM)
+    OutOfMemory = 12,
+    /// 不正なアドレス(EFAULT)
+    BadAddress = 14,
+    /// 不正な引数(EINVAL)
+    InvalidArgument = 22,
+    /// 存在しないシステムコール(ENOSYS)
+    NoSuchSyscall = 38,
+}
+
+impl From<BadAddress> for Errno {
+    fn from(_: BadAddress) -> Self {
+        Errno::BadAddress
+    }
+}
+
+type SyscallResult = Result<u64, Errno>;
+
+/// システムコールの処理
+/// 引数はrdi, rsi, rdx, r10, r8, r9の順に渡される
+type Handler = fn(&[u64; 6]) -> SyscallResult;
+
+/// システムコールの番号から処理を引く表
+static SYSCALL_TABLE: [Handler; 9] = [
+    sys_exit,
+    sys_write,
+    sys_yield,
+    sys_sleep,
+    sys_getpid,
+    sys_mmap,
+    sys_read,
+    sys_uptime,
+    sys_cpu_stats,
+];
+
+/// SYSCALLの入口で保存したレジスタ
+/// `syscall_entry`でスタックに積む順番と対応している
+#[repr(C)]
+#[derive(Debug)]
+struct SyscallFrame {
+    r9: u64,
+    r8: u64,
+    r10: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    /// システムコールの番号で、戻り値もここに入れる
+    rax: u64,
+    /// ユーザーのrip(SYSCALLがrcxに入れる)
+    rip: u64,
+    /// ユーザーのRFLAGS(SYSCALLがr11に入れる)
+    rflags: u64,
+    /// ユーザーのrsp
+    rsp: u64,
+}
+
+// SYSCALLの入口
+// SFMASKで割り込みを禁止した状態で入ってくるので、GSベースとスタックを切り替えてから許可する
+// rcxとr11以外のレジスタは戻り値のraxを除いて元に戻す
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "swapgs",
+    "mov gs:[{user_stack}], rsp",
+    "mov rsp, gs:[{syscall_stack}]",
+    "push qword ptr gs:[{user_stack}]",
+    "push r11",
+    "push rcx",
+    "push rax",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push r10",
+    "push r8",
+    "push r9",
+    "mov rdi, rsp",
+    "sti",
+    "call {dispatch}",
+    "cli",
+    "pop r9",
+    "pop r8",
+    "pop r10",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop rax",
+    "pop rcx",
+    "pop r11",
+    "pop rsp",
+    "swapgs",
+    "sysretq",
+    user_stack = const cpu_local::HEAD_USER_STACK,
+    syscall_stack = const cpu_local::HEAD_SYSCALL_STACK,
+    dispatch = sym syscall_dispatch,
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// 実行中のCPUでSYSCALL命令を使えるようにする
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.code,
+        selectors.data,
+    )
+    .unwrap();
+    LStar::write(VirtAddr::new(
+        syscall_entry as unsafe extern "C" fn() as usize as u64,
+    ));
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    let args = [
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ];
+    let result = match SYSCALL_TABLE.get(frame.rax as usize) {
+        Some(handler) => handler(&args),
+        None => Err(Errno::NoSuchSyscall),
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => (errno as u64).wrapping_neg(),
+    };
+}
+
+fn sys_exit(args: &[u64; 6]) -> SyscallResult {
+    task::exit(args[0]);
+}
+
+fn sys_write(args: &[u64; 6]) -> SyscallResult {
+    let (fd, buf, len) = (args[0], args[1], args[2]);
+    if fd != STDOUT && fd != STDERR {
+        return Err(Errno::BadFileDescriptor);
+    }
+    let len = len.min(WRITE_MAX);
+    let buf = VirtAddr::try_new(buf).map_err(|_| Errno::BadAddress)?;
+
+    // 長い出力の間ずっと割り込みを禁止しないよう、少しずつコピーして書く
+    let mut chunk = [0u8; 64];
+    let mut written = 0;
+    while written < len {
+        let size = (len - written).min(chunk.len() as u64) as usize;
+        if let Err(err) = user_access::copy_from_user(&mut chunk[..size], buf + written) {
+            // 途中まで書けていれば、そこまでの分を返す
+            return if written == 0 {
+                Err(err.into())
+            } else {
+                Ok(written)
+            };
+        }
+        let uart = UART.lock();
+        for &c in &chunk[..size] {
+            unsafe {
+                uart.write(c);
+            }
+        }
+        written += size as u64;
+    }
+    Ok(written)
+}
+
+fn sys_yield(_: &[u64; 6]) -> SyscallResult {
+    task::yield_now();
+    Ok(0)
+}
+
+fn sys_sleep(args: &[u64; 6]) -> SyscallResult {
+    task::sleep_ms(args[0]);
+    Ok(0)
+}
+
+fn sys_getpid(_: &[u64; 6]) -> SyscallResult {
+    Ok(task::current_tid().as_u64())
+}
+
+fn sys_mmap(args: &[u64; 6]) -> SyscallResult {
+    let (addr, len, prot) = (args[0], args[1], args[2]);
+    if len == 0 {
+        return Err(Errno::InvalidArgument);
+    }
+    let len = len
+        .checked_next_multiple_of(PAGE_SIZE)
+        .ok_or(Errno::InvalidArgument)?;
+    let mut flags = PageTableFlags::empty();
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    let space = task::current_address_space().ok_or(Errno::InvalidArgument)?;
+    let mut space = space.lock();
+    let start = if addr == 0 {
+        space.reserve(len).ok_or(Errno::OutOfMemory)?
+    } else {
+        let start = VirtAddr::try_new(addr).map_err(|_| Errno::InvalidArgument)?;
+        if !AddressSpace::is_user_range(start, len) {
+            return Err(Errno::InvalidArgument);
+        }
+        start
+    };
+    space
+        .map_anonymous(start, len, flags)
+        .map_err(|err| match err {
+            MapError::OutOfMemory => Errno::OutOfMemory,
+            MapError::InvalidRange | MapError::AlreadyMapped | MapError::NotMapped => {
+                Errno::InvalidArgument
+            }
+        })?;
+    Ok(start.as_u64())
+}
+
+fn sys_read(args: &[u64; 6]) -> SyscallResult {
+    let (fd, buf, len) = (args[0], args[1], args[2]);
+    if fd != STDIN {
+        return Err(Errno::BadFileDescriptor);
+    }
+    let buf = VirtAddr::try_new(buf).map_err(|_| Errno::BadAddress)?;
+    if len == 0 {
+        return Ok(0);
+    }
+
+    // 1バイト目が来るまでは眠って待ち、その後はすでに受信している分だけを返す
+    let mut chunk = [0u8; 64];
+    let len = len.min(chunk.len() as u64) as usize;
+    let mut read = 0;
+    while read < len {
+        let timeout = if read == 0 { None } else { Some(0) };
+        match uart::read_byte(timeout) {
+            Some(c) => chunk[read] = c,
+            None => break,
+        }
+        read += 1;
+    }
+    user_access::copy_to_user(buf, &chunk[..read])?;
+    Ok(read as u64)
+}
+
+fn sys_uptime(args: &[u64; 6]) -> SyscallResult {
+    UserPtr::<u64>::new(args[0])?.write(lapic::uptime_ms())?;
+    Ok(0)
+}
+
+fn sys_cpu_stats(args: &[u64; 6]) -> SyscallResult {
+    let stats = task::cpu_stats(args[0] as usize).ok_or(Errno::InvalidArgument)?;
+    UserPtr::<CpuStats>::new(args[1])?.write(stats)?;
+    Ok(0)
+}
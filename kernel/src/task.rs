@@ -1,8 +1,27 @@
+use crate::{
+    address_space::AddressSpace,
+    cpu_local, gdt,
+    interrupt::{self, IrqResult, TrapFrame},
+    lapic, memory,
+    smp::{self, CpuMask},
+    sync,
+    wait_queue::WaitQueue,
+};
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
 use spin::Mutex;
-use x86_64::{registers::control::Cr3Flags, structures::paging::frame::PhysFrame, PhysAddr};
+use x86_64::{
+    instructions::interrupts::{self, without_interrupts},
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{frame::PhysFrame, page::PageRange, Page, PageTableFlags},
+    VirtAddr,
+};
 
 /// タスクが今どのような状態なのかを表す
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TaskStatus {
     /// 初期化中のタスク
     Init,
@@ -16,22 +35,188 @@ pub enum TaskStatus {
     Dead,
 }
 
+/// タスクのスケジューリングクラスと、クラスの中での優先度
+/// クラスはRealTime、Normal、Idleの順に優先される
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedClass {
+    /// 固定優先度のタスク(0..=RT_PRIORITY_MAX、大きいほど優先)
+    /// 同じ優先度の中ではタイムスライスごとに順番に実行する
+    RealTime(u8),
+    /// vruntimeで公平に時分割するタスク(NICE_MIN..=NICE_MAX、小さいほど多く実行する)
+    Normal(i8),
+    /// 他に実行可能なタスクがないときだけ実行するタスク
+    Idle,
+}
+
+pub const RT_PRIORITY_MAX: u8 = 99;
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+
+impl SchedClass {
+    /// 優先度が範囲内か
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Self::RealTime(priority) => priority <= RT_PRIORITY_MAX,
+            Self::Normal(nice) => (NICE_MIN..=NICE_MAX).contains(&nice),
+            Self::Idle => true,
+        }
+    }
+}
+
+impl Default for SchedClass {
+    fn default() -> Self {
+        Self::Normal(0)
+    }
+}
+
+/// niceが0のタスクの重み
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// niceごとの重み(NICE_MINから順に、niceが1違うとおよそ1.25倍違う)
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+/// タイマー割り込み1回分の時間(ナノ秒)
+const TICK_NS: u64 = 1_000_000_000 / lapic::TIMER_HZ;
+
+fn nice_to_weight(nice: i8) -> u64 {
+    NICE_TO_WEIGHT[(nice - NICE_MIN) as usize]
+}
+
 static TID_COUNTER: Mutex<u64> = Mutex::new(0);
 
-#[derive(Debug, Clone, Copy)]
-struct Tid(u64);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tid(u64);
 
 impl Tid {
     fn new() -> Self {
+        let _preempt = preempt_disable();
         let mut tid_counter = TID_COUNTER.lock();
         let tid = Self(*tid_counter);
         *tid_counter += 1;
         tid
     }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// カーネルスタックのページ数(64KiB)
+const KERNEL_STACK_PAGES: u64 = 16;
+
+/// カーネルスタックを置く仮想アドレスの範囲の先頭(PML4の510番のエントリ)
+/// 範囲をスタックごとの枠に分け、枠の一番下の1ページはガードページとしてマップしない
+const KERNEL_STACK_AREA: u64 = 0xffff_ff00_0000_0000;
+
+/// カーネルスタック1つ分の枠の大きさ(ガードページを含む)
+const KERNEL_STACK_SLOT_SIZE: u64 = (KERNEL_STACK_PAGES + 1) * memory::PAGE_SIZE;
+
+/// 同時に存在できるカーネルスタックの数
+const MAX_KERNEL_STACKS: usize = 4096;
+
+/// カーネルスタックの枠の使用状況(ビットが立っている枠は使用中)
+static KERNEL_STACK_SLOTS: Mutex<[u64; MAX_KERNEL_STACKS / 64]> =
+    Mutex::new([0; MAX_KERNEL_STACKS / 64]);
+
+/// 新しいタスクのRFLAGSの初期値(割り込み禁止)
+/// スケジューラのロックを外してから`task_start`で割り込みを許可する
+const INITIAL_RFLAGS: u64 = 0x2;
+
+/// ユーザータスクがリング3に入るときのRFLAGS(割り込み許可)
+const USER_RFLAGS: u64 = 0x202;
+
+/// 例外で強制終了したタスクの終了コード
+pub const EXIT_FAULT: u64 = u64::MAX;
+
+/// 負荷分散をするタイマー割り込みの間隔
+const BALANCE_INTERVAL_TICKS: u64 = 10;
+
+/// タイムスライスの初期値(ミリ秒)
+const DEFAULT_QUANTUM_MS: u64 = 50;
+
+/// 1回のタイムスライスのタイマー割り込みの回数
+static QUANTUM_TICKS: AtomicU64 = AtomicU64::new(DEFAULT_QUANTUM_MS * lapic::TIMER_HZ / 1000);
+
+crate::cpu_local! {
+    /// プリエンプションを禁止している区間の深さ
+    static PREEMPT_COUNT: AtomicUsize = AtomicUsize::new(0);
+}
+
+crate::cpu_local! {
+    /// 割り込みから戻る前にタスクを切り替える必要があるか
+    static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+}
+
+/// カーネルスタック専用の仮想アドレスの範囲にマップしたカーネルスタック
+/// 下にガードページがあるので、溢れるとページフォールト(からのダブルフォールト)になる
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    pub fn new() -> Self {
+        let slot = {
+            let _preempt = preempt_disable();
+            let mut slots = KERNEL_STACK_SLOTS.lock();
+            let slot = (0..MAX_KERNEL_STACKS)
+                .find(|&slot| slots[slot / 64] & (1 << (slot % 64)) == 0)
+                .expect("too many kernel stacks");
+            slots[slot / 64] |= 1 << (slot % 64);
+            slot
+        };
+        let stack = Self { slot };
+
+        let pages = stack.pages();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        if memory::map_kernel_pages(pages, flags) != KERNEL_STACK_PAGES {
+            panic!("no memory for a kernel stack");
+        }
+        stack
+    }
+
+    /// ガードページを除いた、スタックとして使うページ
+    fn pages(&self) -> PageRange {
+        let bottom = Page::containing_address(self.guard()) + 1;
+        Page::range(bottom, bottom + KERNEL_STACK_PAGES)
+    }
+
+    /// スタックの一番下にあるガードページの先頭
+    fn guard(&self) -> VirtAddr {
+        VirtAddr::new(KERNEL_STACK_AREA + self.slot as u64 * KERNEL_STACK_SLOT_SIZE)
+    }
+
+    /// スタックの底(最も大きいアドレス)を返す
+    pub fn top(&self) -> VirtAddr {
+        self.guard() + KERNEL_STACK_SLOT_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        // マップできなかったページは無視される
+        memory::unmap_kernel_pages(self.pages());
+        let _preempt = preempt_disable();
+        KERNEL_STACK_SLOTS.lock()[self.slot / 64] &= !(1 << (self.slot % 64));
+    }
+}
+
+/// `addr`がカーネルスタックのガードページの中にあるか
+pub fn is_stack_guard(addr: VirtAddr) -> bool {
+    let offset = match addr.as_u64().checked_sub(KERNEL_STACK_AREA) {
+        Some(offset) => offset,
+        None => return false,
+    };
+    offset < MAX_KERNEL_STACKS as u64 * KERNEL_STACK_SLOT_SIZE
+        && offset % KERNEL_STACK_SLOT_SIZE < memory::PAGE_SIZE
 }
 
 /// プログラムの実行単位
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Task {
     /// Task ID
     /// 実行中のタスクは一意に割り振られる
@@ -45,18 +230,97 @@ pub struct Task {
     p4_table_address: PhysFrame,
     /// cr3のフラグ
     cr3_flags: Cr3Flags,
+    /// カーネルスタック(ブート時のタスクは持たない)
+    kernel_stack: Option<KernelStack>,
+    /// 残りのタイムスライス(タイマー割り込みの回数)
+    slice: u64,
+    /// これまでに実行した時間(タイマー割り込みの回数)
+    runtime_ticks: u64,
+    /// 休眠中のタスクを起こす時刻(起動からのミリ秒)
+    wake_at: Option<u64>,
+    /// スケジューリングクラスと優先度
+    class: SchedClass,
+    /// 重み付けした実行時間(ナノ秒、Normalのタスクだけが使う)
+    vruntime: u64,
+    /// 終了を待つ`JoinHandle`と共有する状態
+    join_state: Option<Arc<JoinState>>,
+    /// 実行してよいCPU
+    affinity: CpuMask,
+    /// リング3で実行するユーザータスクか
+    user: bool,
+    /// ユーザータスクのアドレス空間
+    address_space: Option<Arc<sync::Mutex<AddressSpace>>>,
 }
 
 impl Task {
     /// タスクを生成する
+    /// ページテーブルはカーネルのものを使う
     pub fn new() -> Self {
         Self {
             tid: Tid::new(),
             status: TaskStatus::Init,
             regs: Registers::new(),
-            p4_table_address: PhysFrame::from_start_address(PhysAddr::new(0)).unwrap(),
+            p4_table_address: memory::kernel_p4_frame(),
             cr3_flags: Cr3Flags::empty(),
+            kernel_stack: None,
+            slice: 0,
+            runtime_ticks: 0,
+            wake_at: None,
+            class: SchedClass::default(),
+            vruntime: 0,
+            join_state: None,
+            affinity: CpuMask::all(),
+            user: false,
+            address_space: None,
+        }
+    }
+
+    /// `address_space`の`entry(arg)`からリング3で実行を始めるユーザータスクを生成する
+    /// ユーザースタックは`stack_top`から下に伸びる
+    pub fn new_user(
+        address_space: AddressSpace,
+        entry: VirtAddr,
+        stack_top: VirtAddr,
+        arg: u64,
+    ) -> Self {
+        let mut task = Self::new();
+        let stack = KernelStack::new();
+
+        let rsp = stack.top() - 8u64;
+        unsafe {
+            *rsp.as_mut_ptr::<u64>() = 0;
+        }
+        task.regs.rsp = rsp.as_u64();
+        task.regs.rip = user_task_start as extern "C" fn(u64, u64, u64) -> ! as usize as u64;
+        task.regs.rdi = entry.as_u64();
+        task.regs.rsi = stack_top.as_u64();
+        task.regs.rdx = arg;
+        task.regs.rflags = INITIAL_RFLAGS;
+        task.kernel_stack = Some(stack);
+        task.p4_table_address = address_space.p4_frame();
+        task.user = true;
+        task.address_space = Some(Arc::new(sync::Mutex::new(address_space)));
+        task
+    }
+
+    /// `entry(arg0, arg1)`から実行を始めるカーネルタスクを生成する
+    pub fn new_kernel(entry: extern "C" fn(u64, u64) -> !, arg0: u64, arg1: u64) -> Self {
+        let mut task = Self::new();
+        let stack = KernelStack::new();
+
+        // 関数の先頭ではrspが16バイト境界から8ずれているので、戻り先の分を空けておく
+        let rsp = stack.top() - 8u64;
+        unsafe {
+            *rsp.as_mut_ptr::<u64>() = 0;
         }
+        task.regs.rsp = rsp.as_u64();
+        task.regs.rip = task_start as extern "C" fn(u64, u64, u64) -> ! as usize as u64;
+        task.regs.rdi = arg0;
+        task.regs.rsi = arg1;
+        task.regs.rdx = entry as usize as u64;
+        task.regs.rflags = INITIAL_RFLAGS;
+        task.kernel_stack = Some(stack);
+        task
     }
 
     /// タスクに割り振られているTIDを返す
@@ -69,6 +333,46 @@ impl Task {
         self.status
     }
 
+    /// これまでに実行した時間をミリ秒で返す
+    pub fn runtime_ms(&self) -> u64 {
+        self.runtime_ticks * 1000 / lapic::TIMER_HZ
+    }
+
+    /// スケジューリングクラスと優先度を返す
+    pub fn class(&self) -> SchedClass {
+        self.class
+    }
+
+    /// 実行キューに入れる前にスケジューリングクラスと優先度を設定する
+    pub fn set_class(&mut self, class: SchedClass) {
+        assert!(class.is_valid(), "invalid scheduling class {:?}", class);
+        self.class = class;
+    }
+
+    /// リング3で実行するユーザータスクか
+    pub fn is_user(&self) -> bool {
+        self.user
+    }
+
+    /// 実行してよいCPUを返す
+    pub fn affinity(&self) -> CpuMask {
+        self.affinity
+    }
+
+    /// 実行キューに入れる前に実行してよいCPUを設定する
+    pub fn set_affinity(&mut self, affinity: CpuMask) {
+        assert!(affinity != CpuMask::empty(), "empty CPU affinity");
+        self.affinity = affinity;
+    }
+
+    /// RealTimeのタスクの優先度(それ以外のタスクは0)
+    fn rt_priority(&self) -> u8 {
+        match self.class {
+            SchedClass::RealTime(priority) => priority,
+            _ => 0,
+        }
+    }
+
     /// 保存している汎用レジスタの参照を返す
     fn regs(&self) -> &Registers {
         &self.regs
@@ -86,6 +390,7 @@ impl Task {
 }
 
 /// タスクがスイッチする際に保存するレジスタ
+/// `switch_context`はこの並びを前提にしている
 #[repr(C)]
 #[derive(Debug, Clone)]
 struct Registers {
@@ -133,3 +438,1042 @@ impl Registers {
         }
     }
 }
+
+// switch_context(current: *mut Registers, next: *const Registers)
+// 今のレジスタをcurrentに保存し、nextのレジスタを復元してnext.ripから実行する
+// currentのripとrspには、switch_contextから戻った直後の値を保存する
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "mov [rdi + 0x00], rax",
+    "mov [rdi + 0x08], rbx",
+    "mov [rdi + 0x10], rcx",
+    "mov [rdi + 0x18], rdx",
+    "mov [rdi + 0x20], rdi",
+    "mov [rdi + 0x28], rsi",
+    "mov [rdi + 0x30], r8",
+    "mov [rdi + 0x38], r9",
+    "mov [rdi + 0x40], r10",
+    "mov [rdi + 0x48], r11",
+    "mov [rdi + 0x50], r12",
+    "mov [rdi + 0x58], r13",
+    "mov [rdi + 0x60], r14",
+    "mov [rdi + 0x68], r15",
+    "mov [rdi + 0x70], rbp",
+    "lea rax, [rsp + 8]",
+    "mov [rdi + 0x78], rax",
+    "mov rax, [rsp]",
+    "mov [rdi + 0x80], rax",
+    "pushfq",
+    "pop qword ptr [rdi + 0x88]",
+    // ここからnextに切り替える
+    "mov rsp, [rsi + 0x78]",
+    "push qword ptr [rsi + 0x80]",
+    "push qword ptr [rsi + 0x88]",
+    "mov rax, [rsi + 0x00]",
+    "mov rbx, [rsi + 0x08]",
+    "mov rcx, [rsi + 0x10]",
+    "mov rdx, [rsi + 0x18]",
+    "mov rdi, [rsi + 0x20]",
+    "mov r8, [rsi + 0x30]",
+    "mov r9, [rsi + 0x38]",
+    "mov r10, [rsi + 0x40]",
+    "mov r11, [rsi + 0x48]",
+    "mov r12, [rsi + 0x50]",
+    "mov r13, [rsi + 0x58]",
+    "mov r14, [rsi + 0x60]",
+    "mov r15, [rsi + 0x68]",
+    "mov rbp, [rsi + 0x70]",
+    "mov rsi, [rsi + 0x28]",
+    "popfq",
+    "ret",
+);
+
+// enter_user(entry, stack, arg, code, data)
+// リング3に移り、スタックをstack、rdiをargにしてentryから実行する
+// カーネルのレジスタの値が見えないよう、他の汎用レジスタは0にする
+global_asm!(
+    ".global enter_user",
+    "enter_user:",
+    "push r8",  // SS
+    "push rsi", // RSP
+    "push {rflags}",
+    "push rcx", // CS
+    "push rdi", // RIP
+    "mov rdi, rdx",
+    "xor eax, eax",
+    "xor ebx, ebx",
+    "xor ecx, ecx",
+    "xor edx, edx",
+    "xor esi, esi",
+    "xor ebp, ebp",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r11d, r11d",
+    "xor r12d, r12d",
+    "xor r13d, r13d",
+    "xor r14d, r14d",
+    "xor r15d, r15d",
+    "swapgs",
+    "iretq",
+    rflags = const USER_RFLAGS,
+);
+
+extern "C" {
+    fn switch_context(current: *mut Registers, next: *const Registers);
+    fn enter_user(entry: u64, stack: u64, arg: u64, code: u64, data: u64) -> !;
+}
+
+/// タスクのリスト
+/// `switch`が`Registers`へのポインタを持つので、Vecが伸びてもタスクが動かないようBoxに入れる
+#[allow(clippy::vec_box)]
+type TaskList = Vec<Box<Task>>;
+
+/// 実行可能なタスクをスケジューリングクラスごとに持つキュー
+struct RunQueue {
+    /// RealTimeのタスク(優先度の高い順、同じ優先度なら入った順)
+    realtime: VecDeque<Box<Task>>,
+    /// Normalのタスク(vruntimeの最も小さいものから実行する)
+    normal: TaskList,
+    /// Idleのタスク(入った順)
+    idle: VecDeque<Box<Task>>,
+    /// これまでに選んだNormalのタスクのvruntimeの最大値
+    /// 新しく入ったタスクがCPUを独占しないよう、vruntimeの下限にする
+    min_vruntime: u64,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            realtime: VecDeque::new(),
+            normal: Vec::new(),
+            idle: VecDeque::new(),
+            min_vruntime: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.realtime.is_empty() && self.normal.is_empty() && self.idle.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.realtime.len() + self.normal.len() + self.idle.len()
+    }
+
+    /// `cpu`番のCPUに移せるタスクを1つ取り出す
+    /// 優先度の低いクラスのタスクから選ぶ
+    fn steal(&mut self, cpu: usize) -> Option<Box<Task>> {
+        if let Some(i) = self
+            .idle
+            .iter()
+            .position(|task| task.affinity.contains(cpu))
+        {
+            return self.idle.remove(i);
+        }
+        if let Some(i) = self
+            .normal
+            .iter()
+            .position(|task| task.affinity.contains(cpu))
+        {
+            // vruntimeはCPUごとに基準が違うので、移す先で`min_vruntime`に揃える
+            let mut task = self.normal.swap_remove(i);
+            task.vruntime = 0;
+            return Some(task);
+        }
+        let i = self
+            .realtime
+            .iter()
+            .rposition(|task| task.affinity.contains(cpu))?;
+        self.realtime.remove(i)
+    }
+
+    fn push(&mut self, mut task: Box<Task>) {
+        match task.class {
+            SchedClass::RealTime(priority) => {
+                let index = self
+                    .realtime
+                    .iter()
+                    .position(|queued| queued.rt_priority() < priority)
+                    .unwrap_or(self.realtime.len());
+                self.realtime.insert(index, task);
+            }
+            SchedClass::Normal(_) => {
+                task.vruntime = task.vruntime.max(self.min_vruntime);
+                self.normal.push(task);
+            }
+            SchedClass::Idle => self.idle.push_back(task),
+        }
+    }
+
+    /// Normalのタスクの中で次に実行するものの位置
+    fn next_normal(&self) -> Option<usize> {
+        (0..self.normal.len()).min_by_key(|&i| self.normal[i].vruntime)
+    }
+
+    /// クラス、優先度の順に次に実行するタスクを取り出す
+    fn pop(&mut self) -> Option<Box<Task>> {
+        if let Some(task) = self.realtime.pop_front() {
+            return Some(task);
+        }
+        if let Some(i) = self.next_normal() {
+            let task = self.normal.swap_remove(i);
+            self.min_vruntime = self.min_vruntime.max(task.vruntime);
+            return Some(task);
+        }
+        self.idle.pop_front()
+    }
+
+    fn remove(&mut self, tid: Tid) -> Option<Box<Task>> {
+        if let Some(i) = self.realtime.iter().position(|task| task.tid() == tid) {
+            return self.realtime.remove(i);
+        }
+        if let Some(i) = self.normal.iter().position(|task| task.tid() == tid) {
+            return Some(self.normal.swap_remove(i));
+        }
+        let i = self.idle.iter().position(|task| task.tid() == tid)?;
+        self.idle.remove(i)
+    }
+
+    /// 実行中のタスクより優先されるタスクが待っているか
+    fn outranks(&self, current: &Task) -> bool {
+        match current.class {
+            SchedClass::RealTime(priority) => self
+                .realtime
+                .front()
+                .is_some_and(|task| task.rt_priority() > priority),
+            SchedClass::Normal(_) => !self.realtime.is_empty(),
+            SchedClass::Idle => !self.realtime.is_empty() || !self.normal.is_empty(),
+        }
+    }
+
+    /// 実行中のタスクがCPUを譲るとき、代わりに実行すべきタスクが待っているか
+    fn can_replace(&self, current: &Task) -> bool {
+        match current.class {
+            SchedClass::RealTime(priority) => self
+                .realtime
+                .front()
+                .is_some_and(|task| task.rt_priority() >= priority),
+            SchedClass::Normal(_) => {
+                !self.realtime.is_empty()
+                    || self
+                        .next_normal()
+                        .is_some_and(|i| self.normal[i].vruntime <= current.vruntime)
+            }
+            SchedClass::Idle => !self.is_empty(),
+        }
+    }
+}
+
+/// スケジューリングクラスと優先度に基づくスケジューラ
+struct Scheduler {
+    /// 実行中のタスク
+    current: Option<Box<Task>>,
+    /// 実行可能なタスクのキュー
+    run_queue: RunQueue,
+    /// 実行可能なタスクが無いときに走らせるタスク
+    idle: Option<Box<Task>>,
+    idle_tid: Option<Tid>,
+    /// 起こされるのを待っているタスク
+    sleeping: TaskList,
+    /// 終了したが、まだスタックを解放していないタスク
+    zombies: TaskList,
+    /// このCPUでは実行できないので、他のCPUに移すタスク
+    /// 切り替えが終わるまでは他のCPUに渡せないので、`finish_switch`で移す
+    migrating: TaskList,
+    /// このスケジューラを持つCPUの番号
+    cpu: usize,
+    stats: CpuStats,
+}
+
+/// CPUごとのスケジューラの統計
+/// `SYS_CPU_STATS`でユーザー空間にそのまま書き込むので、レイアウトを固定する
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuStats {
+    /// タスクを切り替えた回数
+    pub context_switches: u64,
+    /// 他のCPUから移ってきたタスクの数
+    pub migrations: u64,
+    /// アイドルタスクを実行していたタイマー割り込みの回数
+    pub idle_ticks: u64,
+    /// それ以外のタスクを実行していたタイマー割り込みの回数
+    pub busy_ticks: u64,
+}
+
+impl CpuStats {
+    const fn new() -> Self {
+        Self {
+            context_switches: 0,
+            migrations: 0,
+            idle_ticks: 0,
+            busy_ticks: 0,
+        }
+    }
+
+    /// アイドルだった時間(ミリ秒)
+    pub fn idle_ms(&self) -> u64 {
+        self.idle_ticks * 1000 / lapic::TIMER_HZ
+    }
+
+    /// タスクを実行していた時間(ミリ秒)
+    pub fn busy_ms(&self) -> u64 {
+        self.busy_ticks * 1000 / lapic::TIMER_HZ
+    }
+}
+
+crate::cpu_local! {
+    /// CPUごとのスケジューラ
+    static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        Self {
+            current: None,
+            run_queue: RunQueue::new(),
+            idle: None,
+            idle_tid: None,
+            sleeping: Vec::new(),
+            zombies: Vec::new(),
+            migrating: Vec::new(),
+            cpu: 0,
+            stats: CpuStats::new(),
+        }
+    }
+
+    /// 次に実行するタスクを選んで`current`を入れ替える
+    /// 切り替えが必要なら、保存先と復元元のレジスタを返す
+    fn switch(&mut self) -> Option<(*mut Registers, *const Registers)> {
+        let idle_tid = self.idle_tid;
+        let current = self.current.as_mut()?;
+        let prev_runnable = current.status == TaskStatus::Run;
+        if prev_runnable {
+            let replace = if Some(current.tid()) == idle_tid {
+                !self.run_queue.is_empty()
+            } else {
+                self.run_queue.can_replace(current)
+            };
+            if !replace {
+                if current.slice == 0 {
+                    current.slice = QUANTUM_TICKS.load(Ordering::Relaxed);
+                }
+                return None;
+            }
+        }
+
+        let mut next = match self.run_queue.pop() {
+            Some(next) => next,
+            None if prev_runnable => return None,
+            None => self.idle.take().expect("no idle task"),
+        };
+        let mut prev = self.current.take().unwrap();
+
+        // リング3から割り込みやSYSCALLで入ってきたら、次のタスクのカーネルスタックを使う
+        if let Some(stack) = &next.kernel_stack {
+            gdt::set_kernel_stack(stack.top());
+            cpu_local::set_syscall_stack(stack.top());
+        }
+        if next.p4_table_address() != prev.p4_table_address() {
+            unsafe {
+                Cr3::write(*next.p4_table_address(), next.cr3_flags());
+            }
+        }
+
+        next.status = TaskStatus::Run;
+        next.slice = QUANTUM_TICKS.load(Ordering::Relaxed);
+        self.stats.context_switches += 1;
+        let prev_regs = &mut prev.regs as *mut Registers;
+        let next_regs = next.regs() as *const Registers;
+        self.current = Some(next);
+
+        match prev.status {
+            TaskStatus::Run => {
+                prev.status = TaskStatus::Wait;
+                if Some(prev.tid()) == self.idle_tid {
+                    self.idle = Some(prev);
+                } else if !prev.affinity.contains(self.cpu) {
+                    self.migrating.push(prev);
+                } else {
+                    self.run_queue.push(prev);
+                }
+            }
+            TaskStatus::Sleep => self.sleeping.push(prev),
+            // 今はまだ終了したタスクのスタックの上にいるので、解放は後で行う
+            TaskStatus::Dead => self.zombies.push(prev),
+            status => panic!("cannot switch away from a {:?} task", status),
+        }
+
+        Some((prev_regs, next_regs))
+    }
+
+    /// タイマー割り込みのたびに実行中のタスクの時間を計上する
+    /// タイムスライスを使い切ったら切り替えを要求する
+    fn tick(&mut self) {
+        self.wake_expired(lapic::uptime_ms());
+
+        let idle_tid = self.idle_tid;
+        let current = match self.current.as_mut() {
+            Some(current) => current,
+            None => return,
+        };
+
+        if Some(current.tid()) == idle_tid {
+            self.stats.idle_ticks += 1;
+        } else {
+            self.stats.busy_ticks += 1;
+        }
+        current.runtime_ticks += 1;
+        current.slice = current.slice.saturating_sub(1);
+        if let SchedClass::Normal(nice) = current.class {
+            current.vruntime += TICK_NS * NICE_0_WEIGHT / nice_to_weight(nice);
+        }
+
+        let resched = if Some(current.tid()) == idle_tid {
+            !self.run_queue.is_empty()
+        } else {
+            self.run_queue.outranks(current)
+                || (current.slice == 0 && self.run_queue.can_replace(current))
+        };
+        if resched {
+            self.request_resched();
+        }
+    }
+
+    /// このスケジューラのCPUに切り替えを要求する
+    /// 他のCPUならIPIで割り込みを起こす
+    fn request_resched(&self) {
+        NEED_RESCHED
+            .get_for(self.cpu)
+            .store(true, Ordering::Relaxed);
+        if self.cpu != smp::current_cpu() {
+            lapic::send_fixed_ipi(smp::apic_id(self.cpu), lapic::RESCHEDULE_VECTOR);
+        }
+    }
+
+    /// 実行中のタスクより優先されるタスクが待っていれば切り替えを要求する
+    fn check_preempt(&self) {
+        let current = match self.current.as_ref() {
+            Some(current) => current,
+            None => return,
+        };
+        let resched = if Some(current.tid()) == self.idle_tid {
+            !self.run_queue.is_empty()
+        } else {
+            self.run_queue.outranks(current)
+        };
+        if resched {
+            self.request_resched();
+        }
+    }
+
+    /// タスクを実行キューに入れる
+    /// このCPUで実行できないタスクは、次の`finish_switch`で他のCPUに移す
+    fn make_runnable(&mut self, mut task: Box<Task>) {
+        task.status = TaskStatus::Wait;
+        task.wake_at = None;
+        if task.affinity.contains(self.cpu) {
+            self.run_queue.push(task);
+            self.check_preempt();
+        } else {
+            self.migrating.push(task);
+            self.request_resched();
+        }
+    }
+
+    /// 実行中のタスクと実行キューのタスクの数
+    fn load(&self) -> usize {
+        let running = self
+            .current
+            .as_ref()
+            .is_some_and(|current| Some(current.tid()) != self.idle_tid);
+        self.run_queue.len() + running as usize
+    }
+
+    /// `tid`のタスクの実行してよいCPUを変える
+    /// 見つからなければ`None`を、他のCPUに移すタスクがあれば`Some(Some(task))`を返す
+    fn set_affinity(&mut self, tid: Tid, affinity: CpuMask) -> Option<Option<Box<Task>>> {
+        if Some(tid) == self.idle_tid {
+            return None;
+        }
+
+        if let Some(current) = self.current.as_mut().filter(|task| task.tid() == tid) {
+            current.affinity = affinity;
+            // 切り替えるときに`migrating`に入る
+            if !affinity.contains(self.cpu) {
+                self.request_resched();
+            }
+            return Some(None);
+        }
+        if let Some(mut task) = self.run_queue.remove(tid) {
+            task.affinity = affinity;
+            if affinity.contains(self.cpu) {
+                self.run_queue.push(task);
+                return Some(None);
+            }
+            return Some(Some(task));
+        }
+        // `migrating`のタスクは`flush_migrations`で新しいaffinityに従って移される
+        let task = self.find_waiting(tid)?;
+        task.affinity = affinity;
+        Some(None)
+    }
+
+    /// `tid`のタスクのスケジューリングクラスと優先度を変える
+    fn set_class(&mut self, tid: Tid, class: SchedClass) -> bool {
+        if Some(tid) == self.idle_tid {
+            return false;
+        }
+
+        if let Some(current) = self.current.as_mut().filter(|task| task.tid() == tid) {
+            current.class = class;
+        } else if let Some(mut task) = self.run_queue.remove(tid) {
+            task.class = class;
+            self.run_queue.push(task);
+        } else if let Some(task) = self.find_waiting(tid) {
+            task.class = class;
+            return true;
+        } else {
+            return false;
+        }
+        self.check_preempt();
+        true
+    }
+
+    /// 休眠中のタスクと、他のCPUに移すのを待っているタスクから`tid`を探す
+    fn find_waiting(&mut self, tid: Tid) -> Option<&mut Box<Task>> {
+        self.sleeping
+            .iter_mut()
+            .chain(self.migrating.iter_mut())
+            .find(|task| task.tid() == tid)
+    }
+
+    /// 起こす時刻が`now`を過ぎたタスクを起こす
+    fn wake_expired(&mut self, now: u64) {
+        let mut i = 0;
+        while i < self.sleeping.len() {
+            if matches!(self.sleeping[i].wake_at, Some(deadline) if deadline <= now) {
+                let task = self.sleeping.swap_remove(i);
+                self.make_runnable(task);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// `tid`のタスクが休眠中なら起こす
+    fn wake(&mut self, tid: Tid) -> bool {
+        // 眠る準備をしてからまだ切り替わっていなければ、眠るのをやめさせる
+        if let Some(current) = self.current.as_mut() {
+            if current.tid() == tid {
+                let sleeping = current.status == TaskStatus::Sleep;
+                if sleeping {
+                    current.status = TaskStatus::Run;
+                    current.wake_at = None;
+                }
+                return sleeping;
+            }
+        }
+
+        if let Some(i) = self.sleeping.iter().position(|task| task.tid() == tid) {
+            let task = self.sleeping.swap_remove(i);
+            self.make_runnable(task);
+            return true;
+        }
+        // `migrating`のタスクはもう実行可能なので、起こすまでもない
+        debug_assert!(self
+            .migrating
+            .iter()
+            .all(|task| task.tid() != tid || task.status == TaskStatus::Wait));
+        false
+    }
+}
+
+/// 今実行しているコードをブート時のタスクとし、アイドルタスクを用意する
+pub fn init() {
+    let mut boot_task = Box::new(Task::new());
+    boot_task.status = TaskStatus::Run;
+
+    let idle = Box::new(Task::new_kernel(idle_main, 0, 0));
+
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.get().lock();
+        scheduler.cpu = smp::current_cpu();
+        scheduler.idle_tid = Some(idle.tid());
+        scheduler.idle = Some(idle);
+        scheduler.current = Some(boot_task);
+    });
+
+    interrupt::register(lapic::TIMER_VECTOR, 0, timer_handler);
+    interrupt::register(lapic::RESCHEDULE_VECTOR, 0, |_, _| IrqResult::Handled);
+}
+
+/// APで今実行しているコードを、そのCPUのアイドルタスクとする
+/// この後`idle_loop`に入ること
+pub fn init_ap() {
+    let mut idle = Box::new(Task::new());
+    idle.status = TaskStatus::Run;
+
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.get().lock();
+        scheduler.cpu = smp::current_cpu();
+        scheduler.idle_tid = Some(idle.tid());
+        scheduler.current = Some(idle);
+    });
+}
+
+fn timer_handler(_: &mut TrapFrame, _: usize) -> IrqResult {
+    let balance = {
+        let mut scheduler = SCHEDULER.get().lock();
+        scheduler.tick();
+        (scheduler.stats.idle_ticks + scheduler.stats.busy_ticks)
+            .is_multiple_of(BALANCE_INTERVAL_TICKS)
+    };
+    flush_migrations();
+    if balance {
+        self::balance();
+    }
+    IrqResult::Handled
+}
+
+extern "C" fn idle_main(_: u64, _: u64) -> ! {
+    idle_loop();
+}
+
+/// アイドルタスクの本体
+/// 他のCPUから仕事をもらえなければ、割り込みが来るまで止まる
+pub fn idle_loop() -> ! {
+    loop {
+        reap();
+        without_interrupts(balance);
+
+        interrupts::disable();
+        if NEED_RESCHED.get().load(Ordering::Relaxed) {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+        yield_now();
+    }
+}
+
+/// 新しいタスクが最初に実行する関数
+/// `schedule`からロックを持ったまま切り替わってくるので、外してから`entry`を呼ぶ
+extern "C" fn task_start(arg0: u64, arg1: u64, entry: u64) -> ! {
+    finish_switch();
+    interrupts::enable();
+    let entry: extern "C" fn(u64, u64) -> ! = unsafe { core::mem::transmute(entry as usize) };
+    entry(arg0, arg1)
+}
+
+/// ユーザータスクが最初に実行する関数
+/// 割り込み禁止のままリング3に移る
+extern "C" fn user_task_start(entry: u64, stack: u64, arg: u64) -> ! {
+    finish_switch();
+    let selectors = gdt::selectors();
+    unsafe {
+        enter_user(
+            entry,
+            stack,
+            arg,
+            selectors.user_code.0 as u64,
+            selectors.user_data.0 as u64,
+        )
+    }
+}
+
+/// タスクを切り替えた後、切り替え先で呼ぶ
+fn finish_switch() {
+    unsafe {
+        SCHEDULER.get().force_unlock();
+    }
+    flush_migrations();
+}
+
+/// 実行できるCPUのうち、一番負荷の低いCPUを選ぶ
+/// 同じ負荷なら実行中のCPUを選ぶ
+fn select_cpu(affinity: CpuMask) -> usize {
+    let current = smp::current_cpu();
+    smp::online_cpus()
+        .filter(|&cpu| affinity.contains(cpu))
+        .min_by_key(|&cpu| (SCHEDULER.get_for(cpu).lock().load(), cpu != current))
+        .expect("no online CPU in the affinity mask")
+}
+
+/// タスクを実行できるCPUの実行キューに入れる
+/// 割り込み禁止状態で呼ぶ
+fn enqueue(task: Box<Task>) {
+    let cpu = select_cpu(task.affinity);
+    SCHEDULER.get_for(cpu).lock().make_runnable(task);
+}
+
+/// `migrating`に溜まったタスクを他のCPUに移す
+/// 割り込み禁止状態で呼ぶ
+fn flush_migrations() {
+    let migrating = core::mem::take(&mut SCHEDULER.get().lock().migrating);
+    for task in migrating {
+        enqueue(task);
+    }
+}
+
+/// 一番負荷の高いCPUの実行キューから、実行中のCPUにタスクを1つ移す
+/// スケジューラのロックは同時に2つ取らない
+/// 割り込み禁止状態で呼ぶ
+fn balance() {
+    let cpu = smp::current_cpu();
+    let load = SCHEDULER.get().lock().load();
+    let busiest = smp::online_cpus()
+        .filter(|&other| other != cpu)
+        .map(|other| (other, SCHEDULER.get_for(other).lock().load()))
+        .max_by_key(|&(_, load)| load);
+    let busiest = match busiest {
+        Some((busiest, busiest_load)) if busiest_load > load + 1 => busiest,
+        _ => return,
+    };
+
+    let task = SCHEDULER.get_for(busiest).lock().run_queue.steal(cpu);
+    if let Some(task) = task {
+        let mut scheduler = SCHEDULER.get().lock();
+        scheduler.stats.migrations += 1;
+        scheduler.make_runnable(task);
+    }
+}
+
+/// 終了したタスクのスタックを解放する
+/// アロケータのロックを取るので、割り込み許可状態で呼ぶこと
+fn reap() {
+    let zombies = without_interrupts(|| core::mem::take(&mut SCHEDULER.get().lock().zombies));
+    drop(zombies);
+}
+
+/// タスクを一番負荷の低いCPUの実行キューに入れる
+pub fn add(task: Task) -> Tid {
+    let tid = task.tid();
+    without_interrupts(|| enqueue(Box::new(task)));
+    tid
+}
+
+/// `spawn`したタスクと`JoinHandle`で共有する終了状態
+#[derive(Debug)]
+struct JoinState {
+    finished: AtomicBool,
+    exit_code: AtomicU64,
+    /// 終了を待っているタスク
+    waiters: WaitQueue,
+}
+
+impl JoinState {
+    fn new() -> Self {
+        Self {
+            finished: AtomicBool::new(false),
+            exit_code: AtomicU64::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+}
+
+/// `spawn`したタスクの終了を待つためのハンドル
+#[derive(Debug)]
+pub struct JoinHandle {
+    tid: Tid,
+    state: Arc<JoinState>,
+}
+
+impl JoinHandle {
+    pub fn tid(&self) -> Tid {
+        self.tid
+    }
+
+    /// タスクが終了しているか
+    pub fn is_finished(&self) -> bool {
+        self.state.finished.load(Ordering::Acquire)
+    }
+
+    /// タスクの終了を待ち、終了コードを返す
+    pub fn join(self) -> u64 {
+        self.state.waiters.wait(|| self.is_finished());
+        self.state.exit_code.load(Ordering::Relaxed)
+    }
+
+    /// タスクの終了を`timeout_ms`ミリ秒まで待ち、終了コードを返す
+    /// 時間切れならハンドルを返す
+    pub fn join_timeout(self, timeout_ms: u64) -> Result<u64, Self> {
+        match self
+            .state
+            .waiters
+            .wait_timeout(Some(timeout_ms), || self.is_finished())
+        {
+            Ok(()) => Ok(self.state.exit_code.load(Ordering::Relaxed)),
+            Err(_) => Err(self),
+        }
+    }
+}
+
+/// `entry(arg)`を実行するカーネルタスクを生成し、実行キューに入れる
+/// `entry`の戻り値がタスクの終了コードになる
+pub fn spawn(entry: fn(u64) -> u64, arg: u64) -> JoinHandle {
+    spawn_with_class(entry, arg, SchedClass::default())
+}
+
+/// `spawn`と同じだが、スケジューリングクラスと優先度を指定する
+pub fn spawn_with_class(entry: fn(u64) -> u64, arg: u64, class: SchedClass) -> JoinHandle {
+    reap();
+
+    let state = Arc::new(JoinState::new());
+    let mut task = Task::new_kernel(spawn_entry, entry as usize as u64, arg);
+    task.set_class(class);
+    task.join_state = Some(state.clone());
+
+    JoinHandle {
+        tid: add(task),
+        state,
+    }
+}
+
+/// `address_space`の`entry(arg)`からリング3で実行するユーザータスクを生成し、実行キューに入れる
+/// ユーザースタックは`stack_top`から下に伸びる
+pub fn spawn_user(
+    address_space: AddressSpace,
+    entry: VirtAddr,
+    stack_top: VirtAddr,
+    arg: u64,
+) -> JoinHandle {
+    reap();
+
+    let state = Arc::new(JoinState::new());
+    let mut task = Task::new_user(address_space, entry, stack_top, arg);
+    task.join_state = Some(state.clone());
+
+    JoinHandle {
+        tid: add(task),
+        state,
+    }
+}
+
+extern "C" fn spawn_entry(entry: u64, arg: u64) -> ! {
+    let entry: fn(u64) -> u64 = unsafe { core::mem::transmute(entry as usize) };
+    exit(entry(arg));
+}
+
+/// 実行中のタスクを終了する
+pub fn exit(code: u64) -> ! {
+    interrupts::disable();
+    let state = SCHEDULER
+        .get()
+        .lock()
+        .current
+        .as_ref()
+        .unwrap()
+        .join_state
+        .clone();
+    if let Some(state) = state {
+        state.exit_code.store(code, Ordering::Relaxed);
+        state.finished.store(true, Ordering::Release);
+        state.waiters.wake_all();
+    }
+    SCHEDULER.get().lock().current.as_mut().unwrap().status = TaskStatus::Dead;
+    schedule();
+    unreachable!("a dead task was scheduled");
+}
+
+/// 実行中のタスクのTIDを返す
+pub fn current_tid() -> Tid {
+    without_interrupts(|| SCHEDULER.get().lock().current.as_ref().unwrap().tid())
+}
+
+/// 実行中のタスクのアドレス空間を返す(カーネルタスクなら`None`)
+pub fn current_address_space() -> Option<Arc<sync::Mutex<AddressSpace>>> {
+    without_interrupts(|| {
+        SCHEDULER
+            .get()
+            .lock()
+            .current
+            .as_ref()
+            .unwrap()
+            .address_space
+            .clone()
+    })
+}
+
+/// 実行中のタスクがリング3で実行するユーザータスクか
+pub fn current_is_user() -> bool {
+    without_interrupts(|| SCHEDULER.get().lock().current.as_ref().unwrap().is_user())
+}
+
+/// 実行中のタスクがこれまでに実行した時間をミリ秒で返す
+pub fn current_runtime_ms() -> u64 {
+    without_interrupts(|| {
+        SCHEDULER
+            .get()
+            .lock()
+            .current
+            .as_ref()
+            .unwrap()
+            .runtime_ms()
+    })
+}
+
+/// 実行中のタスクのスケジューリングクラスと優先度を返す
+pub fn current_class() -> SchedClass {
+    without_interrupts(|| SCHEDULER.get().lock().current.as_ref().unwrap().class())
+}
+
+/// `tid`のタスクのスケジューリングクラスと優先度を変える
+/// 優先度が上がって実行中のタスクより優先されるなら、すぐに切り替わる
+/// そのようなタスクが無ければfalseを返す
+pub fn set_class(tid: Tid, class: SchedClass) -> bool {
+    assert!(class.is_valid(), "invalid scheduling class {:?}", class);
+    let found = without_interrupts(|| {
+        smp::online_cpus().any(|cpu| SCHEDULER.get_for(cpu).lock().set_class(tid, class))
+    });
+    if NEED_RESCHED.get().load(Ordering::Relaxed)
+        && PREEMPT_COUNT.get().load(Ordering::Relaxed) == 0
+        && interrupts::are_enabled()
+    {
+        yield_now();
+    }
+    found
+}
+
+/// `tid`のタスクを実行してよいCPUを変える
+/// 実行中のCPUが含まれなければ、次に切り替えるときに他のCPUに移る
+/// そのようなタスクが無ければfalseを返す
+pub fn set_affinity(tid: Tid, affinity: CpuMask) -> bool {
+    assert!(
+        !affinity.is_offline(),
+        "no online CPU in the affinity {:?}",
+        affinity
+    );
+    let found = without_interrupts(|| {
+        for cpu in smp::online_cpus() {
+            let result = SCHEDULER.get_for(cpu).lock().set_affinity(tid, affinity);
+            match result {
+                Some(Some(task)) => {
+                    enqueue(task);
+                    return true;
+                }
+                Some(None) => return true,
+                None => {}
+            }
+        }
+        false
+    });
+    if NEED_RESCHED.get().load(Ordering::Relaxed)
+        && PREEMPT_COUNT.get().load(Ordering::Relaxed) == 0
+        && interrupts::are_enabled()
+    {
+        yield_now();
+    }
+    found
+}
+
+/// `cpu`番のCPUのスケジューラの統計を返す
+pub fn cpu_stats(cpu: usize) -> Option<CpuStats> {
+    if !smp::is_online(cpu) {
+        return None;
+    }
+    Some(without_interrupts(|| SCHEDULER.get_for(cpu).lock().stats))
+}
+
+/// タイムスライスの長さをミリ秒で設定する
+pub fn set_quantum_ms(ms: u64) {
+    let ticks = (ms * lapic::TIMER_HZ / 1000).max(1);
+    QUANTUM_TICKS.store(ticks, Ordering::Relaxed);
+}
+
+/// 割り込みを禁止した状態でタスクを切り替える
+/// 保存し終わる前に他のCPUがタスクを動かさないよう、ロックを持ったまま切り替え、
+/// 切り替え先の`finish_switch`で外す
+pub fn schedule() {
+    NEED_RESCHED.get().store(false, Ordering::Relaxed);
+    let mut scheduler = SCHEDULER.get().lock();
+    match scheduler.switch() {
+        Some((prev, next)) => {
+            core::mem::forget(scheduler);
+            unsafe {
+                switch_context(prev, next);
+            }
+            finish_switch();
+        }
+        None => {
+            drop(scheduler);
+            flush_migrations();
+        }
+    }
+}
+
+/// 実行中のタスクを眠る状態にする
+/// 割り込み禁止状態で呼び、`schedule`で実際に眠ってから`finish_sleep`を呼ぶ
+/// `deadline`(起動からのミリ秒)を過ぎると`wake`されなくても起きる
+pub fn prepare_to_sleep(deadline: Option<u64>) -> Tid {
+    let mut scheduler = SCHEDULER.get().lock();
+    let current = scheduler.current.as_mut().unwrap();
+    current.status = TaskStatus::Sleep;
+    current.wake_at = deadline;
+    current.tid()
+}
+
+/// 眠る準備を取り消すか、起きた後の後始末をする
+pub fn finish_sleep() {
+    let mut scheduler = SCHEDULER.get().lock();
+    let current = scheduler.current.as_mut().unwrap();
+    current.status = TaskStatus::Run;
+    current.wake_at = None;
+}
+
+/// 休眠中のタスクを起こす
+/// 割り込みハンドラからも呼べる
+pub fn wake(tid: Tid) -> bool {
+    without_interrupts(|| smp::online_cpus().any(|cpu| SCHEDULER.get_for(cpu).lock().wake(tid)))
+}
+
+/// 起動からの時刻が`deadline`ミリ秒になるまで眠る
+pub fn sleep_until(deadline: u64) {
+    while lapic::uptime_ms() < deadline {
+        without_interrupts(|| {
+            prepare_to_sleep(Some(deadline));
+            schedule();
+            finish_sleep();
+        });
+    }
+}
+
+/// `ms`ミリ秒眠る
+/// システムコールからユーザーの値がそのまま渡るので、溢れる分は飽和させる
+pub fn sleep_ms(ms: u64) {
+    sleep_until(lapic::uptime_ms().saturating_add(ms));
+}
+
+/// 他に実行可能なタスクがあればCPUを譲る
+pub fn yield_now() {
+    without_interrupts(schedule);
+}
+
+/// 割り込みの出口で呼ばれ、必要ならプリエンプションする
+pub fn preempt_on_irq_exit() {
+    if PREEMPT_COUNT.get().load(Ordering::Relaxed) == 0
+        && NEED_RESCHED.get().load(Ordering::Relaxed)
+    {
+        schedule();
+    }
+}
+
+/// プリエンプションを禁止している間保持するガード
+/// spin::Mutexを保持する短いクリティカルセクションで使う
+pub struct PreemptGuard(());
+
+/// ガードを破棄するまでプリエンプションを禁止する
+pub fn preempt_disable() -> PreemptGuard {
+    PREEMPT_COUNT.get().fetch_add(1, Ordering::Acquire);
+    PreemptGuard(())
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        let count = PREEMPT_COUNT.get().fetch_sub(1, Ordering::Release);
+        // 禁止中に切り替えが要求されていたら、ここで譲る
+        if count == 1 && NEED_RESCHED.get().load(Ordering::Relaxed) && interrupts::are_enabled() {
+            yield_now();
+        }
+    }
+}
@@ -1,15 +1,12 @@
-use crate::{interrupt, ioapic, print};
-use alloc::sync::Arc;
-use core::fmt::Write;
-use lazy_static::lazy_static;
-use spin::Mutex;
-use x86_64::{
-    instructions::{
-        interrupts::without_interrupts,
-        port::{PortReadOnly, PortWriteOnly},
-    },
-    structures::idt::InterruptStackFrame,
+use crate::{
+    interrupt::{self, IrqResult, TrapFrame},
+    ioapic, print, smp,
+    sync::IrqSpinLock,
+    wait_queue::WaitQueue,
 };
+use alloc::collections::VecDeque;
+use core::fmt::Write;
+use x86_64::instructions::port::{PortReadOnly, PortWriteOnly};
 
 pub const COM1: u16 = 0x3f8;
 pub const COM2: u16 = 0x2f8;
@@ -19,9 +16,15 @@ pub const COM4: u16 = 0x2e8;
 const IRQ_COM1: u32 = 4;
 const IRQ_COM2: u32 = 3;
 
-lazy_static! {
-    pub static ref UART: Arc<Mutex<Uart>> = Arc::new(Mutex::new(Uart { com: COM1 }));
-}
+/// 受信バッファに溜めておく最大のバイト数
+const RX_BUFFER_SIZE: usize = 256;
+
+pub static UART: IrqSpinLock<Uart> = IrqSpinLock::new(Uart { com: COM1 });
+
+/// 割り込みハンドラが受信したバイト
+static RX_BUFFER: IrqSpinLock<VecDeque<u8>> = IrqSpinLock::new(VecDeque::new());
+/// 受信を待っているタスク
+static RX_WAIT: WaitQueue = WaitQueue::new();
 
 pub struct Uart {
     com: u16,
@@ -29,47 +32,50 @@ pub struct Uart {
 
 impl Uart {
     unsafe fn init(&self) {
-        without_interrupts(|| {
-            // 8259 PIC Disable
-            PortWriteOnly::<u8>::new(0xa1).write(0xff);
-            PortWriteOnly::<u8>::new(0x21).write(0xff);
-            // 16550A UART Enable
-            PortWriteOnly::<u8>::new(self.com + 1).write(0); // disable all interrupts
-            PortWriteOnly::<u8>::new(self.com + 3).write(0x80); // DLAB set 1
-            PortWriteOnly::<u8>::new(self.com).write(1); // 115200 / 115200
-            PortWriteOnly::<u8>::new(self.com + 1).write(0); // baud rate hi bytes
-            PortWriteOnly::<u8>::new(self.com + 3).write(0x03); // DLAB set 0
-            PortWriteOnly::<u8>::new(self.com + 4).write(0x0b); // IRQ enable
-            PortWriteOnly::<u8>::new(self.com + 1).write(0x01); // interrupt enable
-
-            if PortReadOnly::<u16>::new(self.com + 5).read() == 0xff {
-                panic!();
-            }
+        // 8259 PIC Disable
+        PortWriteOnly::<u8>::new(0xa1).write(0xff);
+        PortWriteOnly::<u8>::new(0x21).write(0xff);
+        // 16550A UART Enable
+        PortWriteOnly::<u8>::new(self.com + 1).write(0); // disable all interrupts
+        PortWriteOnly::<u8>::new(self.com + 3).write(0x80); // DLAB set 1
+        PortWriteOnly::<u8>::new(self.com).write(1); // 115200 / 115200
+        PortWriteOnly::<u8>::new(self.com + 1).write(0); // baud rate hi bytes
+        PortWriteOnly::<u8>::new(self.com + 3).write(0x03); // DLAB set 0
+        PortWriteOnly::<u8>::new(self.com + 4).write(0x0b); // IRQ enable
+        PortWriteOnly::<u8>::new(self.com + 1).write(0x01); // interrupt enable
+
+        if PortReadOnly::<u16>::new(self.com + 5).read() == 0xff {
+            panic!();
+        }
 
-            PortReadOnly::<u16>::new(self.com + 2).read();
-            PortReadOnly::<u16>::new(self.com).read();
+        PortReadOnly::<u16>::new(self.com + 2).read();
+        PortReadOnly::<u16>::new(self.com).read();
 
-            ioapic::enable(IRQ_COM1, 0);
-        });
+        interrupt::register(
+            (ioapic::T_IRQ0 + IRQ_COM1) as u8,
+            self.com as usize,
+            uart_handler,
+        );
+        ioapic::enable(IRQ_COM1, smp::current_cpu());
     }
 
+    /// 送信バッファが空くのを待って1バイト送る
+    /// printlnは割り込み禁止状態で呼ぶので、眠らずにポーリングする
     pub unsafe fn write(&self, c: u8) {
         if !cfg!(feature = "qemu") {
             while PortReadOnly::<u16>::new(self.com + 5).read() & 0x20 != 0x20 {
-                x86_64::instructions::nop(); // タイマー割り込みがない場合はnopにしないと止まる
+                core::hint::spin_loop();
             }
         }
         PortWriteOnly::<u8>::new(self.com).write(c);
     }
 
-    pub unsafe fn read(&self) -> u8 {
-        if !cfg!(feature = "qemu") {
-            while PortReadOnly::<u16>::new(self.com + 5).read() & 1 != 1 {
-                x86_64::instructions::nop(); // タイマー割り込みがない場合はnopにしないと止まる
-            }
+    /// 受信したバイトがあれば読み出す
+    pub unsafe fn try_read(&self) -> Option<u8> {
+        if !cfg!(feature = "qemu") && PortReadOnly::<u16>::new(self.com + 5).read() & 1 != 1 {
+            return None;
         }
-
-        PortReadOnly::<u16>::new(self.com).read() as u8
+        Some(PortReadOnly::<u16>::new(self.com).read() as u8)
     }
 }
 
@@ -90,13 +96,40 @@ pub fn init() {
     }
 }
 
-pub extern "x86-interrupt" fn uart_handler(_: InterruptStackFrame) {
-    let mut c = b'\0';
-    without_interrupts(|| unsafe {
-        c = UART.lock().read();
-        interrupt::notify_end_of_interrupt();
-        print!("{}", c as char);
-    });
+/// ロックを取らずにUARTに書き込む
+/// ロックを持ったまま止まったCPUがいても出力できるよう、panicやNMIのハンドラで使う
+pub fn write_unlocked(args: core::fmt::Arguments) {
+    let _ = Uart { com: COM1 }.write_fmt(args);
+}
+
+/// 1バイト受信するまで眠って待つ
+/// `timeout_ms`ミリ秒経っても受信しなければ`None`を返す
+pub fn read_byte(timeout_ms: Option<u64>) -> Option<u8> {
+    let mut byte = None;
+    RX_WAIT
+        .wait_timeout(timeout_ms, || {
+            byte = RX_BUFFER.lock().pop_front();
+            byte.is_some()
+        })
+        .ok()?;
+    byte
+}
+
+fn uart_handler(_: &mut TrapFrame, _: usize) -> IrqResult {
+    let c = match unsafe { UART.lock().try_read() } {
+        Some(c) => c,
+        None => return IrqResult::NotMine,
+    };
+    print!("{}", c as char);
+
+    let mut buffer = RX_BUFFER.lock();
+    if buffer.len() == RX_BUFFER_SIZE {
+        buffer.pop_front();
+    }
+    buffer.push_back(c);
+    drop(buffer);
+    RX_WAIT.wake_one();
+    IrqResult::Handled
 }
 
 pub fn remove_screen() {
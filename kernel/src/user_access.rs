@@ -0,0 +1,161 @@
+use crate::{
+    address_space::{USER_SPACE_END, USER_SPACE_START},
+    memory,
+};
+use core::{
+    arch::{asm, global_asm},
+    marker::PhantomData,
+    mem::{size_of, MaybeUninit},
+};
+use x86_64::VirtAddr;
+
+/// ユーザー空間のアドレスが不正だったか、マップされていなかったことを表す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadAddress;
+
+/// 例外が起きても回復できる命令のアドレスと、そのときに再開するアドレス
+#[repr(C)]
+struct FixupEntry {
+    addr: u64,
+    fixup: u64,
+}
+
+extern "C" {
+    static __exception_fixup: FixupEntry;
+    static __exception_fixup_end: FixupEntry;
+
+    /// `len`バイトをコピーし、ページフォールトでコピーできなかったバイト数を返す
+    fn user_copy(dst: *mut u8, src: *const u8, len: usize) -> usize;
+}
+
+// rep movsbの途中でページフォールトが起きたら、残りのバイト数を返すところから再開する
+global_asm!(
+    ".global user_copy",
+    "user_copy:",
+    "mov rcx, rdx",
+    "2:",
+    "rep movsb",
+    "3:",
+    "mov rax, rcx",
+    "ret",
+    ".pushsection .exception_fixup, \"a\"",
+    ".balign 8",
+    ".quad 2b, 3b",
+    ".popsection",
+);
+
+/// `rip`の命令で起きた例外から回復できるなら、再開するアドレスを返す
+pub fn search_fixup(rip: VirtAddr) -> Option<VirtAddr> {
+    let table = unsafe {
+        let start = core::ptr::addr_of!(__exception_fixup);
+        let end = core::ptr::addr_of!(__exception_fixup_end);
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    table
+        .iter()
+        .find(|entry| entry.addr == rip.as_u64())
+        .map(|entry| VirtAddr::new(entry.fixup))
+}
+
+/// `[addr, addr + len)`がユーザー空間に収まっているか調べる
+fn check_range(addr: VirtAddr, len: usize) -> Result<(), BadAddress> {
+    match addr.as_u64().checked_add(len as u64) {
+        Some(end) if addr.as_u64() >= USER_SPACE_START && end <= USER_SPACE_END => Ok(()),
+        _ => Err(BadAddress),
+    }
+}
+
+/// `f`の間だけ、SMAPが有効でもユーザー空間にアクセスできるようにする
+fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let smap = memory::is_smap_enabled();
+    if smap {
+        unsafe { asm!("stac", options(nostack)) };
+    }
+    let result = f();
+    if smap {
+        unsafe { asm!("clac", options(nostack)) };
+    }
+    result
+}
+
+/// `len`バイトをコピーし、途中でページフォールトが起きたら失敗にする
+/// ユーザー空間の側は、呼び出し元で`check_range`しておく
+unsafe fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<(), BadAddress> {
+    match with_user_access(|| user_copy(dst, src, len)) {
+        0 => Ok(()),
+        _ => Err(BadAddress),
+    }
+}
+
+/// ユーザー空間の`src`から`dst`の長さだけコピーする
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), BadAddress> {
+    check_range(src, dst.len())?;
+    unsafe { copy(dst.as_mut_ptr(), src.as_ptr(), dst.len()) }
+}
+
+/// `src`をユーザー空間の`dst`にコピーする
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), BadAddress> {
+    check_range(dst, src.len())?;
+    unsafe { copy(dst.as_mut_ptr(), src.as_ptr(), src.len()) }
+}
+
+/// ユーザー空間にある`T`へのポインタ
+/// 読み書きはバイト列のコピーなので、`T`は整数のようにどんなビット列でも正しい値になる型に限る
+pub struct UserPtr<T> {
+    addr: VirtAddr,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T> core::fmt::Debug for UserPtr<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "UserPtr({:#x})", self.addr.as_u64())
+    }
+}
+
+impl<T: Copy> UserPtr<T> {
+    /// システムコールの引数からポインタを作る
+    pub fn new(addr: u64) -> Result<Self, BadAddress> {
+        let addr = VirtAddr::try_new(addr).map_err(|_| BadAddress)?;
+        check_range(addr, size_of::<T>())?;
+        Ok(Self {
+            addr,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn addr(&self) -> VirtAddr {
+        self.addr
+    }
+
+    /// 指している値を読む
+    pub fn read(&self) -> Result<T, BadAddress> {
+        let mut value = MaybeUninit::<T>::uninit();
+        unsafe {
+            copy(
+                value.as_mut_ptr() as *mut u8,
+                self.addr.as_ptr(),
+                size_of::<T>(),
+            )?;
+            Ok(value.assume_init())
+        }
+    }
+
+    /// 指している場所に`value`を書く
+    pub fn write(&self, value: T) -> Result<(), BadAddress> {
+        unsafe {
+            copy(
+                self.addr.as_mut_ptr(),
+                &value as *const T as *const u8,
+                size_of::<T>(),
+            )
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use crate::{
+    address_space::{AddressSpace, USER_SPACE_START},
+    memory::PAGE_SIZE,
+    syscall,
+    task::{self, JoinHandle},
+};
+use core::arch::global_asm;
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+/// プログラムを置くアドレス
+const PROGRAM_BASE: u64 = USER_SPACE_START;
+
+/// プログラムとスタックの間にある、何もマップしないアドレス
+const UNMAPPED_ADDR: u64 = USER_SPACE_START + 0x10_0000;
+
+/// ユーザースタックの終端
+const STACK_TOP: u64 = USER_SPACE_START + 0x40_0000;
+/// ユーザースタックの大きさ
+const STACK_SIZE: u64 = 0x4000;
+
+// リング3で動かす小さなプログラム
+// 位置に依存しないコードにしておき、ユーザー空間にコピーして実行する
+// システムコールがユーザー空間に正しく書き込めて、不正なアドレスでは失敗すれば0で終了する
+global_asm!(
+    ".pushsection .rodata.user_program, \"a\"",
+    ".global user_program_start",
+    ".global user_program_end",
+    "user_program_start:",
+    // write(STDOUT, message, len)
+    "movl $1, %edi",
+    "leaq 1f(%rip), %rsi",
+    "movl $(2f - 1f), %edx",
+    "movl ${sys_write}, %eax",
+    "syscall",
+    // uptime(スタック上の変数)は成功するはず
+    "subq $16, %rsp",
+    "movq %rsp, %rdi",
+    "movl ${sys_uptime}, %eax",
+    "syscall",
+    "testq %rax, %rax",
+    "jnz 3f",
+    // uptime(マップしていないアドレス)はページフォールトから回復して-EFAULTを返すはず
+    "movl ${unmapped}, %edi",
+    "movl ${sys_uptime}, %eax",
+    "syscall",
+    "cmpq ${efault}, %rax",
+    "jne 3f",
+    // exit(0)
+    "xorl %edi, %edi",
+    "movl ${sys_exit}, %eax",
+    "syscall",
+    // exit(1)
+    "3:",
+    "movl $1, %edi",
+    "movl ${sys_exit}, %eax",
+    "syscall",
+    "ud2",
+    "1:",
+    ".ascii \"[info]hello from ring 3\\n\"",
+    "2:",
+    "user_program_end:",
+    ".popsection",
+    sys_write = const syscall::SYS_WRITE,
+    sys_exit = const syscall::SYS_EXIT,
+    sys_uptime = const syscall::SYS_UPTIME,
+    unmapped = const UNMAPPED_ADDR,
+    efault = const -(syscall::Errno::BadAddress as i64),
+    options(att_syntax),
+);
+
+extern "C" {
+    static user_program_start: u8;
+    static user_program_end: u8;
+}
+
+/// 新しいアドレス空間にプログラムを置き、リング3で実行するタスクを生成する
+pub fn spawn() -> JoinHandle {
+    let program = unsafe {
+        let start = core::ptr::addr_of!(user_program_start);
+        let end = core::ptr::addr_of!(user_program_end);
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    let entry = VirtAddr::new(PROGRAM_BASE);
+    let code_size = (program.len() as u64).next_multiple_of(PAGE_SIZE);
+    let stack_top = VirtAddr::new(STACK_TOP);
+
+    let mut space = AddressSpace::new().expect("no memory for a user address space");
+    space
+        .map_anonymous(entry, code_size, PageTableFlags::empty())
+        .expect("failed to map the user program");
+    space
+        .write(entry, program)
+        .expect("failed to load the user program");
+    space
+        .map_anonymous(
+            stack_top - STACK_SIZE,
+            STACK_SIZE,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )
+        .expect("failed to map the user stack");
+
+    task::spawn_user(space, entry, stack_top, 0)
+}
@@ -0,0 +1,89 @@
+use crate::{
+    interrupt, lapic,
+    task::{self, Tid},
+};
+use alloc::collections::VecDeque;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// 待ちが時間切れで終わったことを表す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+/// 条件が満たされるまでタスクを眠らせておくキュー
+/// 起こす側は割り込みハンドラからでもよい
+#[derive(Debug)]
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<Tid>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// `condition`がtrueを返すまで眠る
+    pub fn wait(&self, condition: impl FnMut() -> bool) {
+        let _ = self.wait_timeout(None, condition);
+    }
+
+    /// `condition`がtrueを返すか、`timeout_ms`ミリ秒経つまで眠る
+    /// `condition`は割り込み禁止状態で呼ばれるので、眠ってはいけない
+    pub fn wait_timeout(
+        &self,
+        timeout_ms: Option<u64>,
+        mut condition: impl FnMut() -> bool,
+    ) -> Result<(), TimedOut> {
+        assert!(
+            !interrupt::in_interrupt(),
+            "cannot sleep in an interrupt handler"
+        );
+        let deadline = timeout_ms.map(|ms| lapic::uptime_ms() + ms);
+        loop {
+            let result = without_interrupts(|| {
+                // 条件を調べる前に眠る準備をしておけば、その間の起床を取りこぼさない
+                let tid = task::prepare_to_sleep(deadline);
+                self.waiters.lock().push_back(tid);
+
+                let result = if condition() {
+                    Some(Ok(()))
+                } else if deadline.is_some_and(|deadline| lapic::uptime_ms() >= deadline) {
+                    Some(Err(TimedOut))
+                } else {
+                    task::schedule();
+                    None
+                };
+
+                task::finish_sleep();
+                self.waiters.lock().retain(|&waiter| waiter != tid);
+                result
+            });
+            if let Some(result) = result {
+                return result;
+            }
+        }
+    }
+
+    /// 待っているタスクを1つ起こす
+    pub fn wake_one(&self) -> bool {
+        without_interrupts(|| loop {
+            let tid = match self.waiters.lock().pop_front() {
+                Some(tid) => tid,
+                None => return false,
+            };
+            if task::wake(tid) {
+                return true;
+            }
+        })
+    }
+
+    /// 待っているタスクをすべて起こし、起こした数を返す
+    pub fn wake_all(&self) -> usize {
+        without_interrupts(|| {
+            let waiters = core::mem::take(&mut *self.waiters.lock());
+            waiters.into_iter().filter(|&tid| task::wake(tid)).count()
+        })
+    }
+}
@@ -6,17 +6,34 @@
 extern crate alloc;
 
 use alloc::vec::Vec;
+use core::arch::asm;
 use goblin::elf::{self, ProgramHeaders};
-use kani2_common::boot::{BootInfo, MemoryMap};
+use kani2_common::boot::{BootInfo, MemoryMap, KERNEL_BASE, PAGE_TABLE_MEMORY, PHYS_MEMORY_OFFSET};
 use uefi::{
     alloc::exit_boot_services,
     prelude::*,
     proto::{self, media::file::*},
-    table::boot::{AllocateType, MemoryDescriptor, MemoryType},
+    table::{
+        boot::{AllocateType, MemoryDescriptor, MemoryType},
+        cfg::ACPI2_GUID,
+    },
 };
 
 const EFI_PAGE_SIZE: usize = 0x1000;
 
+/// ページテーブル1つのエントリ数
+const PAGE_TABLE_ENTRIES: usize = 512;
+/// 2MiBページのサイズ
+const HUGE_PAGE_SIZE: u64 = 0x20_0000;
+/// PDが1つでマップする大きさ(1GiB)
+const PD_COVERAGE: u64 = HUGE_PAGE_SIZE * PAGE_TABLE_ENTRIES as u64;
+/// Local APICやI/O APICのレジスタがあるので、少なくとも4GiBまではマップする
+const MIN_PHYS_MEMORY_END: u64 = 0x1_0000_0000;
+
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITABLE: u64 = 1 << 1;
+const PTE_HUGE_PAGE: u64 = 1 << 7;
+
 #[entry]
 fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     uefi_services::init(&mut system_table).unwrap();
@@ -109,22 +126,53 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
         panic!();
     }
 
+    // カーネルは上位の仮想アドレスにリンクされているので、物理アドレスの方に置く
     for phdr in kernel_elf.program_headers.iter() {
         if phdr.p_type != elf::program_header::PT_LOAD {
             continue;
         }
 
-        let vaddr = phdr.p_vaddr as usize;
-        let memsize = phdr.p_memsz as usize + (vaddr % EFI_PAGE_SIZE);
+        let paddr = phdr.p_paddr as usize;
+        let memsize = phdr.p_memsz as usize + (paddr % EFI_PAGE_SIZE);
 
         let filesize = phdr.p_filesz as usize;
         let offset = phdr.p_offset as usize;
-        let dest = unsafe { core::slice::from_raw_parts_mut(vaddr as *mut u8, memsize) };
+        let dest = unsafe { core::slice::from_raw_parts_mut(paddr as *mut u8, memsize) };
         dest[..filesize].copy_from_slice(&buf[offset..(offset + filesize)]);
         dest[filesize..].fill(0);
     }
     serial.write(b"locate kernel image success\r\n").unwrap();
 
+    // find ACPI 2.0 RSDP
+    let rsdp = system_table
+        .config_table()
+        .iter()
+        .find(|entry| entry.guid == ACPI2_GUID)
+        .map(|entry| entry.address as u64)
+        .unwrap_or(0);
+    if rsdp == 0 {
+        serial.write(b"[WARN]ACPI 2.0 RSDP not found\r\n").unwrap();
+    }
+
+    // build page tables for the kernel
+    let phys_memory_end = match get_memory_map(boot_services) {
+        Ok(memory_map) => phys_memory_end(&memory_map),
+        Err(_) => {
+            serial.write(b"[ERROR]cannot get memory map\r\n").unwrap();
+            panic!();
+        }
+    };
+    let pml4 = match build_page_table(boot_services, phys_memory_end) {
+        Ok(pml4) => pml4,
+        Err(e) => {
+            serial
+                .write(format!("page table allocation failed: {:?}\r\n", e).as_bytes())
+                .unwrap();
+            panic!();
+        }
+    };
+    serial.write(b"build page table success\r\n").unwrap();
+
     let memory_map = get_memory_map(boot_services);
     if memory_map.is_err() {
         serial.write(b"[ERROR]cannot get memory map\r\n").unwrap();
@@ -133,10 +181,14 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
     let memory_map = memory_map.unwrap();
     let mmap = MemoryMap::new(memory_map.as_ptr(), memory_map.len() as u64);
     core::mem::forget(memory_map); // 忘れさせないとRustが開放してしまうかもしれない
-    let boot_info = BootInfo::new(mmap);
+    let boot_info = BootInfo::new(mmap, rsdp);
 
     exit_boot_services();
 
+    // ローダー自身はアイデンティティマップで動き続ける
+    unsafe {
+        asm!("mov cr3, {}", in(reg) pml4, options(nostack, preserves_flags));
+    }
     entry_point(&boot_info);
 
     Status::SUCCESS
@@ -149,9 +201,9 @@ fn calc_alloc_region(phdrs: &ProgramHeaders) -> (usize, usize) {
             continue;
         }
 
-        let vaddr = phdr.p_vaddr as usize;
-        let alloc_top = vaddr - (vaddr % EFI_PAGE_SIZE);
-        let memsize = phdr.p_memsz as usize + (vaddr % EFI_PAGE_SIZE);
+        let paddr = phdr.p_paddr as usize;
+        let alloc_top = paddr - (paddr % EFI_PAGE_SIZE);
+        let memsize = phdr.p_memsz as usize + (paddr % EFI_PAGE_SIZE);
         let page_count = (memsize + EFI_PAGE_SIZE - 1) / EFI_PAGE_SIZE;
 
         if memo.0 == 0 {
@@ -169,6 +221,69 @@ fn calc_alloc_region(phdrs: &ProgramHeaders) -> (usize, usize) {
     memo
 }
 
+/// メモリマップに載っている物理アドレスの終端
+fn phys_memory_end(memory_map: &[MemoryDescriptor]) -> u64 {
+    memory_map
+        .iter()
+        .map(|desc| desc.phys_start + desc.page_count * EFI_PAGE_SIZE as u64)
+        .max()
+        .unwrap_or(0)
+        .max(MIN_PHYS_MEMORY_END)
+}
+
+/// カーネルに渡すページテーブルを作り、PML4の物理アドレスを返す
+///
+/// `phys_memory_end`までの物理メモリを2MiBページで`PHYS_MEMORY_OFFSET`からストレートマップし、
+/// 同じものを0番地からのアイデンティティマップにも使う。
+/// `KERNEL_BASE`からの1GiBは物理アドレス0からにマップする。
+fn build_page_table(boot_services: &BootServices, phys_memory_end: u64) -> uefi::Result<u64> {
+    let pd_count = ((phys_memory_end + PD_COVERAGE - 1) / PD_COVERAGE) as usize;
+    let pdpt_count = (pd_count + PAGE_TABLE_ENTRIES - 1) / PAGE_TABLE_ENTRIES;
+    // PML4, ストレートマップのPDPTとPD, カーネルのPDPT
+    let table_count = 1 + pdpt_count + pd_count + 1;
+    let base =
+        boot_services.allocate_pages(AllocateType::AnyPages, PAGE_TABLE_MEMORY, table_count)?;
+    let tables = unsafe {
+        core::slice::from_raw_parts_mut(base as *mut [u64; PAGE_TABLE_ENTRIES], table_count)
+    };
+    for table in tables.iter_mut() {
+        table.fill(0);
+    }
+    let table_addr = |index: usize| base + (index * EFI_PAGE_SIZE) as u64;
+
+    let pml4 = 0;
+    let pdpt = pml4 + 1;
+    let pd = pdpt + pdpt_count;
+    let kernel_pdpt = pd + pd_count;
+
+    for i in 0..pd_count {
+        for (j, entry) in tables[pd + i].iter_mut().enumerate() {
+            let addr = (i * PAGE_TABLE_ENTRIES + j) as u64 * HUGE_PAGE_SIZE;
+            *entry = addr | PTE_PRESENT | PTE_WRITABLE | PTE_HUGE_PAGE;
+        }
+        tables[pdpt + i / PAGE_TABLE_ENTRIES][i % PAGE_TABLE_ENTRIES] =
+            table_addr(pd + i) | PTE_PRESENT | PTE_WRITABLE;
+    }
+    let offset_index = pml4_index(PHYS_MEMORY_OFFSET);
+    for i in 0..pdpt_count {
+        let entry = table_addr(pdpt + i) | PTE_PRESENT | PTE_WRITABLE;
+        tables[pml4][i] = entry;
+        tables[pml4][offset_index + i] = entry;
+    }
+
+    // カーネルの1GiBは、ストレートマップの最初のPDをそのまま使う
+    tables[kernel_pdpt][(KERNEL_BASE >> 30) as usize % PAGE_TABLE_ENTRIES] =
+        table_addr(pd) | PTE_PRESENT | PTE_WRITABLE;
+    tables[pml4][pml4_index(KERNEL_BASE)] = table_addr(kernel_pdpt) | PTE_PRESENT | PTE_WRITABLE;
+
+    Ok(table_addr(pml4))
+}
+
+/// 仮想アドレスに対応するPML4の添字
+fn pml4_index(addr: u64) -> usize {
+    (addr >> 39) as usize % PAGE_TABLE_ENTRIES
+}
+
 fn get_memory_map(boot_services: &BootServices) -> Result<Vec<MemoryDescriptor>, ()> {
     let mut buf: [u8; 1024 * 16] = [0; 1024 * 16];
     let map = boot_services.memory_map(&mut buf);